@@ -0,0 +1,403 @@
+//! Library to drive a parterm server from Rust.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use parterm::client::Client;
+//! # fn main() -> anyhow::Result<()> {
+//! let client = Client::connect("default")?;
+//! let mut handle = client.run(["cargo", "build"])?;
+//! for output in handle.output_stream() {
+//!     print!("{}", String::from_utf8_lossy(&output?));
+//! }
+//! println!("cargo exited with {}", handle.wait()?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::endpoint;
+use crate::protocol::{
+    client_handshake, read_frame, write_frame, ErrorKind, Reply, Request, RunCommand, ServerInfo,
+};
+use crate::quote::Dialect;
+use crate::screen::Capture;
+use anyhow::{bail, Result};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Connection to a parterm server.
+///
+/// Every request is sent on its own connection, so a `Client` can be used to
+/// send other requests while a command runs.
+#[derive(Debug, Clone)]
+pub struct Client {
+    name: String,
+    socket: PathBuf,
+}
+
+/// A command running in the server terminal.
+pub struct Handle {
+    stream: UnixStream,
+    /// Exit status, once received
+    status: Option<i32>,
+}
+
+/// Iterator over the output of a command, ending when the command exits.
+pub struct OutputStream<'a> {
+    handle: &'a mut Handle,
+}
+
+/// Connection following the server terminal, iterating over its output.
+///
+/// The first output repaints the screen of the server terminal.
+pub struct Attachment {
+    stream: UnixStream,
+    read_only: bool,
+}
+
+/// State of a server found by [`list_servers`].
+pub enum ServerState {
+    Running(ServerInfo),
+    /// The server is gone and left its socket behind
+    Stale,
+    /// The server could not be queried
+    Unreachable(anyhow::Error),
+}
+
+impl Client {
+    /// Connects to the server with the given connection name.
+    ///
+    /// Fails if there is no such server or if it speaks another protocol version.
+    pub fn connect(name: &str) -> Result<Client> {
+        Client::connect_at(name, endpoint::endpoint_path(name)?)
+    }
+
+    /// Connects to the server listening in the directory shared with other
+    /// users, see [`crate::parterm::ServerBuilder::shared_dir`].
+    pub fn connect_shared(dir: &Path, name: &str) -> Result<Client> {
+        endpoint::check_shared_dir(dir)?;
+        Client::connect_at(name, endpoint::socket_path(dir, name)?)
+    }
+
+    fn connect_at(name: &str, socket: PathBuf) -> Result<Client> {
+        let mut stream = match endpoint::connect_at(&socket) {
+            Ok(stream) => stream,
+            Err(err) => bail!("No server open for {}: {}", name, err),
+        };
+        client_handshake(&mut stream)?;
+        Ok(Client {
+            name: name.to_string(),
+            socket,
+        })
+    }
+
+    /// Name of the connection.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the program with its arguments in the server terminal.
+    ///
+    /// The arguments are quoted for the server shell, which sees them as they
+    /// are given. The returned handle gives the output and the exit status of
+    /// the program.
+    pub fn run<I, S>(&self, argv: I) -> Result<Handle>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.run_command(RunCommand {
+            argv: argv.into_iter().map(Into::into).collect(),
+            tee: true,
+            ..Default::default()
+        })
+    }
+
+    /// Runs the command in the server terminal.
+    ///
+    /// The returned handle gives the exit status of the command and, if
+    /// `command.tee` is set, its output.
+    pub fn run_command(&self, command: RunCommand) -> Result<Handle> {
+        let command = RunCommand {
+            wait: true,
+            ..command
+        };
+        Ok(Handle {
+            stream: self.send(&Request::RunCommand(command))?,
+            status: None,
+        })
+    }
+
+    /// Runs the command in the server terminal without waiting for it.
+    pub fn execute(&self, command: RunCommand) -> Result<()> {
+        self.request(&Request::RunCommand(command))
+    }
+
+    /// Runs a task defined in the server, see [`ServerInfo::tasks`].
+    ///
+    /// The returned handle gives the exit status of the task and, if `tee` is
+    /// set, its output.
+    pub fn run_task(&self, task: &str, tee: bool) -> Result<Handle> {
+        let request = Request::RunTask {
+            task: task.to_string(),
+            wait: true,
+            tee,
+        };
+        Ok(Handle {
+            stream: self.send(&request)?,
+            status: None,
+        })
+    }
+
+    /// Runs a task defined in the server without waiting for it.
+    pub fn execute_task(&self, task: &str) -> Result<()> {
+        self.request(&Request::RunTask {
+            task: task.to_string(),
+            wait: false,
+            tee: false,
+        })
+    }
+
+    /// Writes the bytes to the server terminal as if they were typed.
+    pub fn send_keys(&self, data: &[u8]) -> Result<()> {
+        self.request(&Request::SendKeys {
+            data: data.to_vec(),
+        })
+    }
+
+    /// Types the named keys, like `C-c`, `Enter` or `Up`, in the server terminal.
+    ///
+    /// The keys are encoded for the mode of the terminal by the server, see
+    /// [`crate::keys`] for their names.
+    pub fn send_named_keys<I, S>(&self, keys: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.request(&Request::SendNamedKeys {
+            keys: keys.into_iter().map(Into::into).collect(),
+        })
+    }
+
+    /// Interrupts the program running in the server terminal.
+    pub fn interrupt(&self) -> Result<()> {
+        self.request(&Request::Interrupt)
+    }
+
+    /// Resizes the server terminal.
+    pub fn resize(&self, width: u16, height: u16) -> Result<()> {
+        self.request(&Request::Resize { width, height })
+    }
+
+    /// Returns the state of the server.
+    pub fn status(&self) -> Result<ServerInfo> {
+        let mut stream = self.send(&Request::Query)?;
+        match read_reply(&mut stream)? {
+            Reply::Info(info) => Ok(*info),
+            reply => bail!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Tells if a command can run in the directory: the server shell is
+    /// already there, or it is a shell the directory can be quoted for.
+    pub fn can_run_in(&self, dir: &Path) -> Result<bool> {
+        let info = self.status()?;
+        Ok(info.cwd.as_deref() == Some(dir) || Dialect::detect(&info.shell).is_some())
+    }
+
+    /// Starts recording the server terminal in the asciicast v2 format.
+    ///
+    /// The file is written by the server, a relative path is relative to the
+    /// current directory.
+    pub fn start_recording<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = std::env::current_dir()?.join(path);
+        self.request(&Request::StartRecording { path })
+    }
+
+    /// Stops the recording of the server terminal.
+    pub fn stop_recording(&self) -> Result<()> {
+        self.request(&Request::StopRecording)
+    }
+
+    /// Returns what the server terminal shows, with up to `history` lines of
+    /// its scrollback.
+    pub fn capture(&self, history: usize) -> Result<Capture> {
+        let mut stream = self.send(&Request::Capture { history })?;
+        match read_reply(&mut stream)? {
+            Reply::Capture(capture) => Ok(capture),
+            reply => bail!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Waits for the server terminal to print text matching the regex pattern,
+    /// without the escape sequences. The last `recent` lines already printed
+    /// are matched too.
+    ///
+    /// Returns the matched text, or None when nothing matched before the timeout.
+    pub fn expect(
+        &self,
+        pattern: &str,
+        timeout: Option<Duration>,
+        recent: usize,
+    ) -> Result<Option<String>> {
+        let mut stream = self.send(&Request::Expect {
+            pattern: pattern.to_string(),
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+            recent,
+        })?;
+        match read_frame(&mut stream)? {
+            Some(Reply::Matched { text }) => Ok(Some(text)),
+            Some(Reply::Error {
+                kind: ErrorKind::Timeout,
+                ..
+            }) => Ok(None),
+            Some(Reply::Error { kind, message }) => bail!("{:?}: {}", kind, message),
+            Some(reply) => bail!("Unexpected reply {:?}", reply),
+            None => bail!("The server closed the connection"),
+        }
+    }
+
+    /// Follows the server terminal, as its screen then as it changes.
+    ///
+    /// Unless `read_only` is set, keys can be typed with the attachment.
+    pub fn attach(&self, read_only: bool) -> Result<Attachment> {
+        Ok(Attachment {
+            stream: self.send(&Request::Attach { read_only })?,
+            read_only,
+        })
+    }
+
+    /// Connects to the server and sends the request once the handshake is done.
+    fn send(&self, request: &Request) -> Result<UnixStream> {
+        let mut stream = endpoint::connect_at(&self.socket)?;
+        client_handshake(&mut stream)?;
+        write_frame(&mut stream, request)?;
+        Ok(stream)
+    }
+
+    /// Sends a request answered with [`Reply::Accepted`].
+    fn request(&self, request: &Request) -> Result<()> {
+        let mut stream = self.send(request)?;
+        match read_reply(&mut stream)? {
+            Reply::Accepted => Ok(()),
+            reply => bail!("Unexpected reply {:?}", reply),
+        }
+    }
+}
+
+impl Handle {
+    /// Returns the output of the command as it is printed.
+    ///
+    /// It is empty unless the command was run with `tee` set.
+    pub fn output_stream(&mut self) -> OutputStream<'_> {
+        OutputStream { handle: self }
+    }
+
+    /// Waits for the command to exit and returns its exit status.
+    ///
+    /// The output not read yet is dropped.
+    pub fn wait(mut self) -> Result<i32> {
+        while let Some(output) = self.next_output() {
+            output?;
+        }
+        match self.status {
+            Some(status) => Ok(status),
+            None => bail!("The server closed the connection before the command exited"),
+        }
+    }
+
+    fn next_output(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.status.is_some() {
+            return None;
+        }
+        match read_reply(&mut self.stream) {
+            Ok(Reply::Output { data }) => Some(Ok(data)),
+            Ok(Reply::Exited { status }) => {
+                self.status = Some(status);
+                None
+            }
+            Ok(reply) => Some(Err(anyhow::anyhow!("Unexpected reply {:?}", reply))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+impl Iterator for OutputStream<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.handle.next_output()
+    }
+}
+
+impl Attachment {
+    /// Writes the bytes to the server terminal as if they were typed.
+    pub fn send_keys(&self, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            bail!("Attached read only");
+        }
+        let request = Request::SendKeys {
+            data: data.to_vec(),
+        };
+        write_frame(&mut &self.stream, &request)?;
+        Ok(())
+    }
+
+    /// Returns another attachment on the same connection, to type keys while
+    /// the output is read.
+    pub fn try_clone(&self) -> Result<Attachment> {
+        Ok(Attachment {
+            stream: self.stream.try_clone()?,
+            read_only: self.read_only,
+        })
+    }
+
+    /// Detaches from the server terminal, ending the output.
+    pub fn detach(&self) -> Result<()> {
+        self.stream.shutdown(std::net::Shutdown::Both)?;
+        Ok(())
+    }
+}
+
+impl Iterator for Attachment {
+    type Item = Result<Vec<u8>>;
+
+    /// Returns the next output, ending when the client detaches or the
+    /// server shell exits.
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_frame(&mut self.stream) {
+            Ok(Some(Reply::Output { data })) => Some(Ok(data)),
+            Ok(Some(Reply::Error { kind, message })) => {
+                Some(Err(anyhow::anyhow!("{:?}: {}", kind, message)))
+            }
+            Ok(Some(reply)) => Some(Err(anyhow::anyhow!("Unexpected reply {:?}", reply))),
+            Ok(None) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+/// Reads the next reply, turning the errors sent by the server into errors.
+fn read_reply(stream: &mut UnixStream) -> Result<Reply> {
+    match read_frame(stream)? {
+        Some(Reply::Error { kind, message }) => bail!("{:?}: {}", kind, message),
+        Some(reply) => Ok(reply),
+        None => bail!("The server closed the connection"),
+    }
+}
+
+/// Returns the state of every server, by name.
+pub fn list_servers() -> Result<Vec<(String, ServerState)>> {
+    Ok(endpoint::list()?
+        .into_iter()
+        .map(|name| {
+            let state = match Client::connect(&name).and_then(|client| client.status()) {
+                Ok(info) => ServerState::Running(info),
+                Err(_) if endpoint::is_stale(&name) => ServerState::Stale,
+                Err(err) => ServerState::Unreachable(err),
+            };
+            (name, state)
+        })
+        .collect())
+}
@@ -0,0 +1,528 @@
+//! Settings read from the configuration files.
+//!
+//! The user settings are in `$XDG_CONFIG_HOME/parterm/config.toml`, or
+//! `~/.config/parterm/config.toml`, and the project ones in the first
+//! `.parterm.toml` found from the current directory up. Every setting is
+//! optional and the sources win in this order:
+//!
+//! 1. the command line options,
+//! 2. the `[sessions.NAME]` table of the session, over the `[server]` one,
+//! 3. the project file,
+//! 4. the user file,
+//! 5. the defaults.
+//!
+//! Relative paths are relative to the directory of the file they are in, and
+//! `~/` is the home directory.
+//!
+//! ```toml
+//! name = "build"
+//! socket_dir = "~/.parterm"
+//! log = "info"
+//!
+//! [server]
+//! shell = "/bin/zsh"
+//! scrollback = 5000
+//! record = true
+//!
+//! [sessions.build]
+//! command = "cd ~/src/project"
+//!
+//! [client]
+//! if_busy = "interrupt"
+//! cwd = "shell"
+//!
+//! [keys]
+//! detach = "C-q"
+//!
+//! [record]
+//! dir = "~/casts"
+//!
+//! [tasks.test]
+//! argv = ["cargo", "test"]
+//! cwd = "."
+//! env = { RUST_BACKTRACE = "1" }
+//! if_busy = "interrupt"
+//! ```
+
+use crate::protocol::{BusyPolicy, InterruptTimeouts, RunCommand};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Name of the project files.
+pub const PROJECT_FILE: &str = ".parterm.toml";
+
+/// File marking the root of a project, holding its session name or empty to
+/// use the directory name.
+pub const MARKER_FILE: &str = ".parterm";
+
+/// Settings of every source, merged.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Session used when none is given, instead of the project one
+    pub name: Option<String>,
+    /// Directory of the sockets, instead of the runtime directory
+    pub socket_dir: Option<PathBuf>,
+    /// What is logged, like `info` or `parterm=debug`, `RUST_LOG` wins
+    pub log: Option<String>,
+    /// Directory of the log files, the logs go to stderr without it
+    pub log_dir: Option<PathBuf>,
+    pub server: ServerConfig,
+    /// Server settings of a session, over the `server` ones
+    pub sessions: HashMap<String, ServerConfig>,
+    pub client: ClientConfig,
+    pub keys: KeysConfig,
+    pub record: RecordConfig,
+    /// Commands run by name, a project task replaces a user one
+    pub tasks: HashMap<String, TaskConfig>,
+}
+
+/// Settings of the `server` command.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Shell run in the terminal, instead of `$SHELL`
+    pub shell: Option<String>,
+    /// Command run once the shell started
+    pub command: Option<String>,
+    pub scrollback: Option<usize>,
+    pub shell_integration: Option<bool>,
+    /// Records the terminal in the recording directory
+    pub record: Option<bool>,
+}
+
+/// Settings of the `client` command.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub if_busy: Option<BusyPolicy>,
+    /// Seconds before an interrupted program gets SIGTERM
+    pub term_after: Option<f64>,
+    /// Seconds after SIGTERM before it gets SIGKILL
+    pub kill_after: Option<f64>,
+    pub cwd: Option<CwdMode>,
+    pub paste: Option<bool>,
+}
+
+/// Where the commands run.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CwdMode {
+    /// In the current directory of the client
+    Client,
+    /// In the current directory of the server shell
+    Shell,
+}
+
+/// Keys of the `attach` command, named as for `send-keys`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Detaches, `C-]` by default
+    pub detach: Option<String>,
+}
+
+/// Settings of the recordings.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Directory of the recordings given with a relative path
+    pub dir: Option<PathBuf>,
+}
+
+/// A command run by name with `parterm run NAME`, with either `command` or
+/// `argv`. The busy policy and timeouts default to the client ones.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskConfig {
+    /// Shell code typed as it is
+    pub command: Option<String>,
+    /// Program and arguments, quoted for the server shell
+    pub argv: Option<Vec<String>>,
+    /// Directory the task runs in, the server shell one by default
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    pub if_busy: Option<BusyPolicy>,
+    pub term_after: Option<f64>,
+    pub kill_after: Option<f64>,
+    pub paste: Option<bool>,
+}
+
+impl Config {
+    /// Reads the user file and the project file found from the directory.
+    pub fn load(dir: &Path) -> Result<Config> {
+        let mut config = Config::default();
+        if let Some(path) = user_file().filter(|path| path.is_file()) {
+            config.merge(Config::read(&path)?);
+        }
+        if let Some(path) = project_file(dir) {
+            config.merge(Config::read(&path)?);
+        }
+        Ok(config)
+    }
+
+    /// Reads a file, making its paths absolute.
+    pub fn read(path: &Path) -> Result<Config> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Unable to read {:?}", path))?;
+        let mut config: Config =
+            toml::from_str(&text).with_context(|| format!("Invalid configuration {:?}", path))?;
+        let base = path.parent().unwrap_or(Path::new("/"));
+        for path in [
+            &mut config.socket_dir,
+            &mut config.log_dir,
+            &mut config.record.dir,
+        ]
+        .into_iter()
+        .chain(config.tasks.values_mut().map(|task| &mut task.cwd))
+        .flatten()
+        {
+            *path = resolve(path, base);
+        }
+        Ok(config)
+    }
+
+    /// Overrides these settings with the ones set in the other.
+    pub fn merge(&mut self, other: Config) {
+        merge(&mut self.name, other.name);
+        merge(&mut self.socket_dir, other.socket_dir);
+        merge(&mut self.log, other.log);
+        merge(&mut self.log_dir, other.log_dir);
+        self.server.merge(other.server);
+        for (name, server) in other.sessions {
+            self.sessions.entry(name).or_default().merge(server);
+        }
+        merge(&mut self.client.if_busy, other.client.if_busy);
+        merge(&mut self.client.term_after, other.client.term_after);
+        merge(&mut self.client.kill_after, other.client.kill_after);
+        merge(&mut self.client.cwd, other.client.cwd);
+        merge(&mut self.client.paste, other.client.paste);
+        merge(&mut self.keys.detach, other.keys.detach);
+        merge(&mut self.record.dir, other.record.dir);
+        self.tasks.extend(other.tasks);
+    }
+
+    /// Server settings of the session.
+    pub fn server(&self, name: &str) -> ServerConfig {
+        let mut server = self.server.clone();
+        if let Some(session) = self.sessions.get(name) {
+            server.merge(session.clone());
+        }
+        server
+    }
+
+    /// Commands of the tasks, by name.
+    pub fn tasks(&self) -> Result<HashMap<String, RunCommand>> {
+        self.tasks
+            .iter()
+            .map(|(name, task)| Ok((name.clone(), task.command(&self.client, name)?)))
+            .collect()
+    }
+}
+
+impl TaskConfig {
+    fn command(&self, client: &ClientConfig, name: &str) -> Result<RunCommand> {
+        let (argv, raw) = match (&self.command, &self.argv) {
+            (Some(command), None) => (vec![command.clone()], true),
+            (None, Some(argv)) if !argv.is_empty() => (argv.clone(), false),
+            _ => bail!("Task {} needs either a command or an argv", name),
+        };
+        let millis = |seconds: Option<f64>, default| match seconds {
+            Some(seconds) => (seconds.max(0.0) * 1000.0) as u64,
+            None => default,
+        };
+        let defaults = InterruptTimeouts::default();
+        Ok(RunCommand {
+            argv,
+            raw,
+            cwd: self.cwd.clone(),
+            env: self.env.clone().into_iter().collect(),
+            paste: self.paste.or(client.paste).unwrap_or(false),
+            policy: self.if_busy.or(client.if_busy).unwrap_or_default(),
+            timeouts: InterruptTimeouts {
+                term_after_ms: millis(
+                    self.term_after.or(client.term_after),
+                    defaults.term_after_ms,
+                ),
+                kill_after_ms: millis(
+                    self.kill_after.or(client.kill_after),
+                    defaults.kill_after_ms,
+                ),
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl ServerConfig {
+    fn merge(&mut self, other: ServerConfig) {
+        merge(&mut self.shell, other.shell);
+        merge(&mut self.command, other.command);
+        merge(&mut self.scrollback, other.scrollback);
+        merge(&mut self.shell_integration, other.shell_integration);
+        merge(&mut self.record, other.record);
+    }
+}
+
+fn merge<T>(setting: &mut Option<T>, other: Option<T>) {
+    if other.is_some() {
+        *setting = other;
+    }
+}
+
+/// Path of the user file.
+pub fn user_file() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => home_dir()?.join(".config"),
+    };
+    Some(dir.join("parterm").join("config.toml"))
+}
+
+/// Returns the first project file found from the directory up.
+pub fn project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Returns the session name of the project the directory is in: the name in
+/// the nearest `.parterm` marker or, when it is empty or when the git root
+/// is nearer, the name of the project root followed by a hash of its path,
+/// so that two checkouts of a project get their own sessions.
+pub fn project_name(dir: &Path) -> Option<String> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    dir.ancestors().find_map(|dir| {
+        let marker = dir.join(MARKER_FILE);
+        if marker.exists() {
+            let text = fs::read_to_string(marker).unwrap_or_default();
+            let name = text.lines().next().unwrap_or_default().trim();
+            if !name.is_empty() {
+                return Some(sanitize_name(name));
+            }
+        } else if !dir.join(".git").exists() {
+            return None;
+        }
+        // The root of the file system has no name
+        let name = sanitize_name(&dir.file_name()?.to_string_lossy());
+        Some(format!("{}-{:08x}", name, path_hash(dir) as u32))
+    })
+}
+
+/// Makes the name usable as a connection name, replacing what the socket
+/// file name can not hold. It does not start like a hidden file or an option.
+fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    match name.trim_start_matches(['.', '-']) {
+        "" => "project".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// FNV-1a hash of the path, the same from one build to the other.
+fn path_hash(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// Makes the path absolute, expanding `~/`.
+fn resolve(path: &Path, base: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(path), Some(home)) => home.join(path),
+        _ => base.join(path),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_settings_override_the_user_ones() {
+        let mut config: Config = toml::from_str(
+            r#"
+            name = "main"
+            [server]
+            shell = "/bin/zsh"
+            scrollback = 100
+            [sessions.build]
+            command = "make"
+            [client]
+            if_busy = "reject"
+            cwd = "shell"
+            "#,
+        )
+        .unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [server]
+                scrollback = 5000
+                [sessions.build]
+                shell = "/bin/bash"
+                [client]
+                if_busy = "interrupt"
+                "#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(config.name.as_deref(), Some("main"));
+        assert_eq!(config.client.if_busy, Some(BusyPolicy::Interrupt));
+        assert_eq!(config.client.cwd, Some(CwdMode::Shell));
+
+        let build = config.server("build");
+        assert_eq!(build.shell.as_deref(), Some("/bin/bash"));
+        assert_eq!(build.command.as_deref(), Some("make"));
+        assert_eq!(build.scrollback, Some(5000));
+        let other = config.server("other");
+        assert_eq!(other.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(other.command, None);
+    }
+
+    #[test]
+    fn project_files_are_found_from_the_subdirectories() {
+        let root = env::temp_dir().join(format!("parterm_config_test_{}", std::process::id()));
+        let sub = root.join("src").join("bin");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(
+            root.join(PROJECT_FILE),
+            "log_dir = \"logs\"\n[record]\ndir = \"/casts\"\n",
+        )
+        .unwrap();
+
+        let path = project_file(&sub).unwrap();
+        assert_eq!(path, root.join(PROJECT_FILE));
+        let config = Config::read(&path).unwrap();
+        assert_eq!(config.log_dir, Some(root.join("logs")));
+        assert_eq!(config.record.dir, Some(PathBuf::from("/casts")));
+
+        fs::write(root.join(PROJECT_FILE), "[server]\nshel = \"sh\"\n").unwrap();
+        assert!(Config::read(&path).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn tasks_become_commands() {
+        let path = env::temp_dir().join(format!("parterm_task_test_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            [client]
+            if_busy = "reject"
+            kill_after = 0.5
+            [tasks.build]
+            command = "make -j8 && ./check"
+            [tasks.test]
+            argv = ["cargo", "test"]
+            cwd = "crate"
+            env = { RUST_LOG = "debug" }
+            if_busy = "interrupt"
+            [tasks.empty]
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(config.tasks().is_err());
+        config.tasks.remove("empty");
+
+        let tasks = config.tasks().unwrap();
+        let build = &tasks["build"];
+        assert_eq!(build.argv, ["make -j8 && ./check"]);
+        assert!(build.raw);
+        assert_eq!(build.cwd, None);
+        assert_eq!(build.policy, BusyPolicy::Reject);
+        let test = &tasks["test"];
+        assert_eq!(test.argv, ["cargo", "test"]);
+        assert!(!test.raw);
+        assert_eq!(test.cwd, Some(env::temp_dir().join("crate")));
+        assert_eq!(test.env, [("RUST_LOG".to_string(), "debug".to_string())]);
+        assert_eq!(test.policy, BusyPolicy::Interrupt);
+        assert_eq!(test.timeouts.term_after_ms, 2000);
+        assert_eq!(test.timeouts.kill_after_ms, 500);
+    }
+
+    #[test]
+    fn sessions_are_named_after_the_project() {
+        let root = env::temp_dir().join(format!("parterm_name_test_{}", std::process::id()));
+        let repo = root.join("repo");
+        let crate_dir = repo.join("crates").join("server");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(crate_dir.join("src")).unwrap();
+        let hashed = |name: &str, dir: &Path| {
+            format!(
+                "{}-{:08x}",
+                name,
+                path_hash(&dir.canonicalize().unwrap()) as u32
+            )
+        };
+        assert_eq!(
+            project_name(&crate_dir.join("src")),
+            Some(hashed("repo", &repo))
+        );
+
+        // The nearest marker wins, with the name it holds
+        fs::write(crate_dir.join(MARKER_FILE), "").unwrap();
+        assert_eq!(
+            project_name(&crate_dir.join("src")),
+            Some(hashed("server", &crate_dir))
+        );
+        fs::write(crate_dir.join(MARKER_FILE), "repo-server\n").unwrap();
+        assert_eq!(project_name(&crate_dir).as_deref(), Some("repo-server"));
+        assert_eq!(project_name(&repo), Some(hashed("repo", &repo)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checkouts_with_the_same_name_get_their_own_sessions() {
+        let root = env::temp_dir().join(format!("parterm_checkout_test_{}", std::process::id()));
+        let first = root.join("first").join("api");
+        let second = root.join("second").join("api");
+        fs::create_dir_all(first.join(".git")).unwrap();
+        fs::create_dir_all(second.join(".git")).unwrap();
+        let first_name = project_name(&first).unwrap();
+        let second_name = project_name(&second).unwrap();
+        assert!(first_name.starts_with("api-"), "{}", first_name);
+        assert!(second_name.starts_with("api-"), "{}", second_name);
+        assert_ne!(first_name, second_name);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn project_names_are_valid_connection_names() {
+        let root = env::temp_dir().join(format!("parterm_sanitize_test_{}", std::process::id()));
+        let dotted = root.join(".dotfiles");
+        let spaced = root.join("my project");
+        fs::create_dir_all(dotted.join(".git")).unwrap();
+        fs::create_dir_all(&spaced).unwrap();
+        fs::write(spaced.join(MARKER_FILE), "../web app\n").unwrap();
+
+        let name = project_name(&dotted).unwrap();
+        assert!(name.starts_with("dotfiles-"), "{}", name);
+        assert!(crate::endpoint::socket_path(&root, &name).is_ok());
+        let name = project_name(&spaced).unwrap();
+        assert_eq!(name, "web-app");
+        assert!(crate::endpoint::socket_path(&root, &name).is_ok());
+        assert_eq!(sanitize_name("..."), "project");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+//! Unix sockets the servers listen on, one per connection name.
+//!
+//! The sockets live in a directory private to the user, so other users can
+//! neither connect to a server nor take its name first:
+//! `$PARTERM_SOCKET_DIR` when it is set, `$XDG_RUNTIME_DIR/parterm/` or, when
+//! neither is set, `parterm-<uid>/` in the temporary directory. The directory
+//! must belong to the user and must not be accessible by anybody else.
+//!
+//! To let other users connect, the sockets are put in a shared directory
+//! instead, `$PARTERM_SHARED_DIR` or the one given to the server and client.
+//! Only its owner may be able to write to it, so the clients trust the sockets
+//! created by the owner, and the servers accept the allowed users only.
+
+use anyhow::{bail, Result};
+use log::debug;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::Uid;
+use std::env::{self, temp_dir};
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+const SUFFIX: &str = ".sock";
+
+/// Environment variable giving the directory of the sockets.
+pub const SOCKET_DIR_VAR: &str = "PARTERM_SOCKET_DIR";
+
+/// Environment variable giving a directory of sockets shared with other users.
+pub const SHARED_DIR_VAR: &str = "PARTERM_SHARED_DIR";
+
+/// Returns the directory of the sockets, creating it if needed.
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dir = match (
+        env::var_os(SOCKET_DIR_VAR).map(PathBuf::from),
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+    ) {
+        (Some(dir), _) if dir.is_absolute() => dir,
+        (_, Some(runtime_dir)) if runtime_dir.is_absolute() => runtime_dir.join("parterm"),
+        _ => temp_dir().join(format!("parterm-{}", Uid::current())),
+    };
+    if !dir.exists() {
+        debug!("create runtime dir {:?}", dir);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    }
+    check_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Returns the directory of the sockets, the shared one if it is set.
+pub fn socket_dir() -> Result<PathBuf> {
+    match env::var_os(SHARED_DIR_VAR).map(PathBuf::from) {
+        Some(dir) => {
+            check_shared_dir(&dir)?;
+            Ok(dir)
+        }
+        None => runtime_dir(),
+    }
+}
+
+/// Makes sure only the owner of the shared directory can create sockets in it.
+pub fn check_shared_dir(dir: &Path) -> Result<()> {
+    if !dir.is_absolute() {
+        bail!("The shared directory {:?} is not absolute", dir);
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        bail!("{:?} is not a directory", dir);
+    }
+    if metadata.mode() & 0o022 != 0 {
+        bail!(
+            "{:?} can be written by other users, its mode is {:o}",
+            dir,
+            metadata.mode() & 0o777
+        );
+    }
+    Ok(())
+}
+
+/// Makes sure only the current user can use the directory.
+fn check_private_dir(dir: &Path) -> Result<()> {
+    // Not following symlinks, the directory itself must be ours
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        bail!("{:?} is not a directory", dir);
+    }
+    if metadata.uid() != Uid::current().as_raw() {
+        bail!("{:?} belongs to another user", dir);
+    }
+    if metadata.mode() & 0o077 != 0 {
+        bail!(
+            "{:?} can be accessed by other users, its mode is {:o}",
+            dir,
+            metadata.mode() & 0o777
+        );
+    }
+    Ok(())
+}
+
+/// Makes sure the socket was created by the current user, or by the owner of
+/// its directory when it is shared.
+fn check_owner(socket_file: &Path) -> Result<()> {
+    let owner = fs::symlink_metadata(socket_file)?.uid();
+    let dir_owner = match socket_file.parent() {
+        Some(dir) => fs::symlink_metadata(dir)?.uid(),
+        None => bail!("{:?} is not in a directory", socket_file),
+    };
+    if owner != Uid::current().as_raw() && owner != dir_owner {
+        bail!("{:?} belongs to another user", socket_file);
+    }
+    Ok(())
+}
+
+/// Returns the path of the socket for the given connection name.
+pub fn endpoint_path(name: &str) -> Result<PathBuf> {
+    socket_path(&socket_dir()?, name)
+}
+
+/// Returns the path of the socket for the given connection name in the directory.
+pub fn socket_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+        bail!("Invalid connection name {:?}", name);
+    }
+    Ok(dir.join(format!("{}{}", name, SUFFIX)))
+}
+
+/// Creates the socket the server listens on.
+///
+/// A socket left behind by a server that is gone is replaced.
+pub fn bind(name: &str) -> Result<UnixListener> {
+    bind_at(&endpoint_path(name)?)
+}
+
+/// Creates the socket at the given path, see [`bind`].
+///
+/// The socket can only be used by the current user, unless its directory is
+/// accessible by others: the server checks the users connecting then.
+pub fn bind_at(socket_file: &Path) -> Result<UnixListener> {
+    debug!("socket_file {:?}", socket_file);
+    if fs::symlink_metadata(socket_file).is_ok() {
+        check_owner(socket_file)?;
+        if UnixStream::connect(socket_file).is_ok() {
+            bail!("A server is already running at {:?}", socket_file);
+        }
+        debug!("remove stale socket_file {:?}", socket_file);
+        fs::remove_file(socket_file)?;
+    }
+    let shared = match socket_file.parent() {
+        Some(dir) => fs::metadata(dir)?.mode() & 0o077 != 0,
+        None => false,
+    };
+    let listener = UnixListener::bind(socket_file)?;
+    let mode = if shared { 0o666 } else { 0o600 };
+    fs::set_permissions(socket_file, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+pub fn connect(name: &str) -> Result<UnixStream> {
+    match connect_at(&endpoint_path(name)?) {
+        Ok(stream) => Ok(stream),
+        Err(err) => bail!("No server open for {}: {}", name, err),
+    }
+}
+
+/// Connects to the socket at the given path, if it can be trusted.
+pub fn connect_at(socket_file: &Path) -> Result<UnixStream> {
+    debug!("socket_file {:?}", socket_file);
+    check_owner(socket_file)?;
+    Ok(UnixStream::connect(socket_file)?)
+}
+
+pub fn delete(name: &str) -> Result<()> {
+    let socket_file = endpoint_path(name)?;
+    debug!("remove socket_file {:?}", socket_file);
+    Ok(fs::remove_file(socket_file)?)
+}
+
+/// Returns the names of all the sockets, live or not, sorted.
+pub fn list() -> Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(socket_dir()?)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            let name = file_name.strip_suffix(SUFFIX)?;
+            Some(name.to_string())
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Returns the user id of the process at the other end of the connection.
+pub fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    Ok(getsockopt(stream.as_raw_fd(), PeerCredentials)?.uid())
+}
+
+/// Tells if nobody listens on the socket anymore, meaning its server crashed.
+pub fn is_stale(name: &str) -> bool {
+    match endpoint_path(name).map(UnixStream::connect) {
+        Ok(Ok(_)) | Err(_) => false,
+        Ok(Err(err)) => err.kind() == io::ErrorKind::ConnectionRefused,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets_are_listed_and_detected_stale_once_closed() {
+        let name = format!("endpoint_test_{}", std::process::id());
+        let listener = bind(&name).unwrap();
+        assert!(list().unwrap().contains(&name));
+        assert!(!is_stale(&name));
+        assert!(bind(&name).is_err());
+
+        let mode = fs::metadata(endpoint_path(&name).unwrap()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        drop(listener);
+        assert!(is_stale(&name));
+        // A stale socket is replaced
+        let _listener = bind(&name).unwrap();
+        delete(&name).unwrap();
+        assert!(!list().unwrap().contains(&name));
+    }
+
+    #[test]
+    fn peer_uid_is_the_current_user() {
+        let (stream, _) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&stream).unwrap(), Uid::current().as_raw());
+    }
+
+    #[test]
+    fn runtime_dir_must_be_private() {
+        let dir = temp_dir().join(format!("parterm_dir_test_{}", std::process::id()));
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert!(check_private_dir(&dir).is_ok());
+
+        fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        assert!(check_private_dir(&dir).is_err());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_dirs_must_only_be_writable_by_their_owner() {
+        let dir = temp_dir().join(format!("parterm_shared_test_{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        assert!(check_shared_dir(&dir).is_ok());
+        // The other users can connect, the server checks who they are
+        let socket_file = socket_path(&dir, "shared").unwrap();
+        let _listener = bind_at(&socket_file).unwrap();
+        let mode = fs::metadata(&socket_file).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o666);
+        assert!(connect_at(&socket_file).is_ok());
+        fs::remove_file(&socket_file).unwrap();
+
+        fs::set_permissions(&dir, Permissions::from_mode(0o775)).unwrap();
+        assert!(check_shared_dir(&dir).is_err());
+        assert!(check_shared_dir(Path::new("relative")).is_err());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_can_not_escape_the_runtime_dir() {
+        assert!(endpoint_path("../other").is_err());
+        assert!(endpoint_path("..").is_err());
+        assert!(endpoint_path("").is_err());
+        assert!(endpoint_path("my-project").is_ok());
+    }
+}
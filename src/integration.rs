@@ -0,0 +1,189 @@
+//! Shell integration with OSC 133 prompt markers.
+//!
+//! The shells started by the server are set up to print the markers used by
+//! many terminals to find the prompts and the commands in their output:
+//!
+//! * `ESC ] 133 ; A BEL` when the prompt is printed
+//! * `ESC ] 133 ; B BEL` at the end of the prompt, where the command is typed
+//! * `ESC ] 133 ; C BEL` when the command starts
+//! * `ESC ] 133 ; D ; <status> BEL` when the command finished
+//!
+//! bash, zsh and fish are supported. Their own configuration is read first.
+
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Start of the markers, before their kind.
+pub const MARKER_OSC: &[u8] = b"\x1b]133;";
+
+/// A marker printed by an integrated shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    /// The prompt is printed
+    PromptStart,
+    /// The prompt is printed, the command is typed after it
+    CommandInput,
+    /// The command typed starts
+    CommandStart,
+    /// The command finished with the exit status, if known
+    CommandEnd(Option<i32>),
+}
+
+/// Shells that can be integrated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellKind {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const BASH_INTEGRATION: &str = r#"# parterm shell integration
+if [ -f ~/.bashrc ]; then . ~/.bashrc; fi
+__parterm_prompt() {
+    local status=$?
+    printf '\033]133;D;%s\007\033]133;A\007' "$status"
+    return $status
+}
+# Run last, after the hooks rewriting the prompt
+__parterm_input() {
+    local status=$?
+    case "$PS1" in
+    *'133;B'*) ;;
+    *) PS1="$PS1\[\e]133;B\a\]" ;;
+    esac
+    return $status
+}
+if [[ $(declare -p PROMPT_COMMAND 2>/dev/null) == "declare -a"* ]] &&
+    (( BASH_VERSINFO[0] * 100 + BASH_VERSINFO[1] >= 501 )); then
+    PROMPT_COMMAND=(__parterm_prompt "${PROMPT_COMMAND[@]}" __parterm_input)
+else
+    # Hooks often end with ';', which can not be followed by another one
+    __parterm_hooks=$PROMPT_COMMAND
+    while [[ $__parterm_hooks == *[\;[:space:]] ]]; do
+        __parterm_hooks=${__parterm_hooks%?}
+    done
+    PROMPT_COMMAND="__parterm_prompt${__parterm_hooks:+;$__parterm_hooks};__parterm_input"
+    unset __parterm_hooks
+fi
+PS0="$PS0\e]133;C\a"
+"#;
+
+const ZSHENV_INTEGRATION: &str = r#"# parterm shell integration
+if [ -f "${PARTERM_ZDOTDIR:-$HOME}/.zshenv" ]; then . "${PARTERM_ZDOTDIR:-$HOME}/.zshenv"; fi
+"#;
+
+const ZSHRC_INTEGRATION: &str = r#"# parterm shell integration
+ZDOTDIR="${PARTERM_ZDOTDIR:-$HOME}"
+unset PARTERM_ZDOTDIR
+if [ -f "$ZDOTDIR/.zshrc" ]; then . "$ZDOTDIR/.zshrc"; fi
+__parterm_precmd() { printf '\033]133;D;%s\007\033]133;A\007' "$?" }
+__parterm_preexec() { printf '\033]133;C\007' }
+__parterm_input() { [[ $PS1 == *'133;B'* ]] || PS1="$PS1%{$(printf '\033]133;B\007')%}" }
+precmd_functions=(__parterm_precmd $precmd_functions __parterm_input)
+preexec_functions+=(__parterm_preexec)
+"#;
+
+const FISH_INTEGRATION: &str = r#"# parterm shell integration
+function __parterm_prompt --on-event fish_prompt
+    printf '\e]133;A\a'
+end
+function __parterm_preexec --on-event fish_preexec
+    printf '\e]133;C\a'
+end
+function __parterm_postexec --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end
+functions --copy fish_prompt __parterm_fish_prompt
+function fish_prompt
+    __parterm_fish_prompt
+    printf '\e]133;B\a'
+end
+"#;
+
+impl ShellKind {
+    /// Finds the kind of shell from the path of its program.
+    pub fn detect(program: &str) -> Option<ShellKind> {
+        match Path::new(program).file_name()?.to_str()? {
+            "bash" => Some(ShellKind::Bash),
+            "zsh" => Some(ShellKind::Zsh),
+            "fish" => Some(ShellKind::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the shell command up to print the markers, all of them.
+///
+/// The scripts doing it are written in the given private directory.
+pub fn inject(cmd: &mut Command, kind: ShellKind, dir: &Path) -> io::Result<()> {
+    match kind {
+        ShellKind::Bash => {
+            let rcfile = dir.join("bash-integration.sh");
+            fs::write(&rcfile, BASH_INTEGRATION)?;
+            cmd.arg("--rcfile").arg(rcfile);
+        }
+        ShellKind::Zsh => {
+            let zdotdir = dir.join("zsh-integration");
+            if !zdotdir.exists() {
+                DirBuilder::new().mode(0o700).create(&zdotdir)?;
+            }
+            fs::write(zdotdir.join(".zshenv"), ZSHENV_INTEGRATION)?;
+            fs::write(zdotdir.join(".zshrc"), ZSHRC_INTEGRATION)?;
+            if let Some(user_zdotdir) = std::env::var_os("ZDOTDIR") {
+                cmd.env("PARTERM_ZDOTDIR", user_zdotdir);
+            }
+            cmd.env("ZDOTDIR", zdotdir);
+        }
+        ShellKind::Fish => {
+            let script = dir.join("fish-integration.fish");
+            fs::write(&script, FISH_INTEGRATION)?;
+            cmd.arg("--init-command")
+                .arg(format!("source '{}'", script.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Parses the body of a marker, what follows [`MARKER_OSC`].
+pub fn parse_marker(body: &[u8]) -> Option<Marker> {
+    let body = std::str::from_utf8(body).ok()?;
+    let mut params = body.split(';');
+    match params.next()? {
+        "A" => Some(Marker::PromptStart),
+        "B" => Some(Marker::CommandInput),
+        "C" => Some(Marker::CommandStart),
+        "D" => Some(Marker::CommandEnd(
+            params.next().and_then(|status| status.parse().ok()),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_are_parsed_with_their_status() {
+        assert_eq!(parse_marker(b"A"), Some(Marker::PromptStart));
+        assert_eq!(parse_marker(b"A;cl=m"), Some(Marker::PromptStart));
+        assert_eq!(parse_marker(b"C"), Some(Marker::CommandStart));
+        assert_eq!(parse_marker(b"D;127"), Some(Marker::CommandEnd(Some(127))));
+        assert_eq!(parse_marker(b"D"), Some(Marker::CommandEnd(None)));
+        assert_eq!(parse_marker(b"Z"), None);
+    }
+
+    #[test]
+    fn shells_are_detected_from_their_path() {
+        assert_eq!(ShellKind::detect("/bin/bash"), Some(ShellKind::Bash));
+        assert_eq!(ShellKind::detect("zsh"), Some(ShellKind::Zsh));
+        assert_eq!(
+            ShellKind::detect("/usr/local/bin/fish"),
+            Some(ShellKind::Fish)
+        );
+        assert_eq!(ShellKind::detect("/bin/sh"), None);
+    }
+}
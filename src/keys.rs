@@ -0,0 +1,396 @@
+//! Named keys and the sequences a terminal sends for them.
+//!
+//! Keys are named like in tmux: `Enter`, `Escape`, `Up`, `F5`, `PageDown`,
+//! and a single character is the key typing it. They take the `C-` (control),
+//! `M-` (meta or alt) and `S-` (shift) modifiers, as in `C-c` or `M-Left`.
+//!
+//! The cursor keys send other sequences when the program asked for the
+//! application cursor mode (DECCKM), [`TerminalModes`] follows it and the
+//! bracketed paste mode from the terminal output.
+
+/// A key with its modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+/// Keys without their modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Char(char),
+    Enter,
+    Tab,
+    BackTab,
+    Escape,
+    Backspace,
+    Up,
+    Down,
+    Right,
+    Left,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// Function keys, from 1 to 12, see [`KeyCode::function`]
+    F(u8),
+}
+
+/// Private modes set by the program running in the terminal.
+#[derive(Debug, Default)]
+pub struct TerminalModes {
+    /// The cursor keys send `ESC O` sequences instead of `ESC [` ones
+    pub application_cursor: bool,
+    /// Pasted text is put between `ESC [ 200 ~` and `ESC [ 201 ~`
+    pub bracketed_paste: bool,
+    parser: ModeParser,
+}
+
+/// Where the parser is in a sequence.
+#[derive(Debug, Default)]
+enum ModeParser {
+    #[default]
+    Ground,
+    Escape,
+    /// Control sequence, with its parameters so far when they are private ones
+    Csi(Option<Vec<u8>>),
+}
+
+/// Longest parameters of a private mode sequence, longer ones are ignored.
+const MAX_PARAMS_LEN: usize = 32;
+
+impl TerminalModes {
+    /// Follows the modes set in the output of the terminal.
+    ///
+    /// The sequences can be split between calls.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.parser = match (std::mem::take(&mut self.parser), byte) {
+                (_, 0x1b) => ModeParser::Escape,
+                (ModeParser::Escape, b'[') => ModeParser::Csi(Some(Vec::new())),
+                // Full reset
+                (ModeParser::Escape, b'c') => {
+                    self.application_cursor = false;
+                    self.bracketed_paste = false;
+                    ModeParser::Ground
+                }
+                (ModeParser::Csi(Some(params)), b'?') if params.is_empty() => {
+                    ModeParser::Csi(Some(vec![b'?']))
+                }
+                (ModeParser::Csi(Some(mut params)), b'0'..=b'9' | b';')
+                    if params.len() < MAX_PARAMS_LEN =>
+                {
+                    params.push(byte);
+                    ModeParser::Csi(Some(params))
+                }
+                (ModeParser::Csi(params), 0x40..=0x7e) => {
+                    if let Some(params) = params {
+                        self.set_modes(&params, byte);
+                    }
+                    ModeParser::Ground
+                }
+                (ModeParser::Csi(_), 0x20..=0x3f) => ModeParser::Csi(None),
+                _ => ModeParser::Ground,
+            };
+        }
+    }
+
+    fn set_modes(&mut self, params: &[u8], action: u8) {
+        let enabled = match action {
+            b'h' => true,
+            b'l' => false,
+            _ => return,
+        };
+        let params = match params.strip_prefix(b"?") {
+            Some(params) => params,
+            None => return,
+        };
+        for param in params.split(|&byte| byte == b';') {
+            match param {
+                b"1" => self.application_cursor = enabled,
+                b"2004" => self.bracketed_paste = enabled,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Key {
+    /// Parses a key name with its modifiers, like `C-c`, `Enter` or `M-S-Up`.
+    ///
+    /// Returns None when it is not a key, it is then text to type as it is.
+    pub fn parse(spec: &str) -> Option<Key> {
+        let mut key = Key {
+            code: KeyCode::Enter,
+            ctrl: false,
+            alt: false,
+            shift: false,
+        };
+        let mut name = spec;
+        loop {
+            let (modifier, rest) = match name.split_at_checked(2) {
+                Some((modifier, rest)) if !rest.is_empty() => (modifier, rest),
+                _ => break,
+            };
+            match modifier {
+                "C-" | "c-" => key.ctrl = true,
+                "M-" | "m-" => key.alt = true,
+                "S-" | "s-" => key.shift = true,
+                _ => break,
+            }
+            name = rest;
+        }
+        if let Some(rest) = name
+            .strip_prefix('^')
+            .filter(|rest| rest.chars().count() == 1)
+        {
+            key.ctrl = true;
+            name = rest;
+        }
+        let mut chars = name.chars();
+        key.code = match (chars.next()?, chars.next()) {
+            (c, None) => KeyCode::Char(c),
+            _ => KeyCode::from_name(name)?,
+        };
+        Some(key)
+    }
+
+    /// Returns the bytes the terminal sends for the key, None for the
+    /// function keys beyond F12.
+    pub fn encode(&self, modes: &TerminalModes) -> Option<Vec<u8>> {
+        // xterm modifier parameter of the special keys
+        let modifier = 1 + u8::from(self.shift) + 2 * u8::from(self.alt) + 4 * u8::from(self.ctrl);
+        let cursor = |letter: char| {
+            if modifier > 1 {
+                format!("\x1b[1;{}{}", modifier, letter).into_bytes()
+            } else if modes.application_cursor {
+                format!("\x1bO{}", letter).into_bytes()
+            } else {
+                format!("\x1b[{}", letter).into_bytes()
+            }
+        };
+        let tilde = |number: u8| {
+            if modifier > 1 {
+                format!("\x1b[{};{}~", number, modifier).into_bytes()
+            } else {
+                format!("\x1b[{}~", number).into_bytes()
+            }
+        };
+        let simple = |bytes: &[u8]| {
+            let mut sequence = Vec::new();
+            if self.alt {
+                sequence.push(0x1b);
+            }
+            sequence.extend_from_slice(bytes);
+            sequence
+        };
+        let sequence = match self.code {
+            KeyCode::Char(c) => {
+                let c = match c {
+                    c if self.ctrl => match control_char(c) {
+                        Some(control) => control,
+                        None => c,
+                    },
+                    c if self.shift => c.to_uppercase().next().unwrap_or(c),
+                    c => c,
+                };
+                simple(c.to_string().as_bytes())
+            }
+            KeyCode::Enter => simple(b"\r"),
+            KeyCode::Tab if self.shift => b"\x1b[Z".to_vec(),
+            KeyCode::Tab => simple(b"\t"),
+            KeyCode::BackTab => b"\x1b[Z".to_vec(),
+            KeyCode::Escape => simple(b"\x1b"),
+            KeyCode::Backspace => simple(b"\x7f"),
+            KeyCode::Up => cursor('A'),
+            KeyCode::Down => cursor('B'),
+            KeyCode::Right => cursor('C'),
+            KeyCode::Left => cursor('D'),
+            KeyCode::Home => cursor('H'),
+            KeyCode::End => cursor('F'),
+            KeyCode::Insert => tilde(2),
+            KeyCode::Delete => tilde(3),
+            KeyCode::PageUp => tilde(5),
+            KeyCode::PageDown => tilde(6),
+            KeyCode::F(number @ 1..=4) => {
+                let letter = (b'P' + number - 1) as char;
+                if modifier > 1 {
+                    format!("\x1b[1;{}{}", modifier, letter).into_bytes()
+                } else {
+                    format!("\x1bO{}", letter).into_bytes()
+                }
+            }
+            KeyCode::F(number @ 5..=12) => {
+                tilde([15, 17, 18, 19, 20, 21, 23, 24][number as usize - 5])
+            }
+            KeyCode::F(_) => return None,
+        };
+        Some(sequence)
+    }
+}
+
+impl KeyCode {
+    /// Returns the function key with the number, from 1 to 12.
+    pub fn function(number: u8) -> Option<KeyCode> {
+        (1..=12).contains(&number).then_some(KeyCode::F(number))
+    }
+
+    /// Finds the key from its name, ignoring the case.
+    fn from_name(name: &str) -> Option<KeyCode> {
+        let name = name.to_ascii_lowercase();
+        let code = match name.as_str() {
+            "enter" | "return" | "cr" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "btab" | "backtab" => KeyCode::BackTab,
+            "escape" | "esc" => KeyCode::Escape,
+            "bspace" | "backspace" => KeyCode::Backspace,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "right" => KeyCode::Right,
+            "left" => KeyCode::Left,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "ic" | "insert" => KeyCode::Insert,
+            "dc" | "delete" => KeyCode::Delete,
+            "ppage" | "pageup" | "pgup" => KeyCode::PageUp,
+            "npage" | "pagedown" | "pgdn" => KeyCode::PageDown,
+            _ => KeyCode::function(name.strip_prefix('f')?.parse().ok()?)?,
+        };
+        Some(code)
+    }
+}
+
+/// Returns the control character typed with Ctrl and the character.
+fn control_char(c: char) -> Option<char> {
+    let control = match c {
+        'a'..='z' | 'A'..='Z' => c.to_ascii_uppercase() as u8 - b'@',
+        '@' | ' ' | '2' => 0,
+        '[' | '3' => 0x1b,
+        '\\' | '4' => 0x1c,
+        ']' | '5' => 0x1d,
+        '^' | '6' => 0x1e,
+        '_' | '7' | '/' => 0x1f,
+        '?' | '8' => 0x7f,
+        _ => return None,
+    };
+    Some(control as char)
+}
+
+/// Encodes the keys one after the other, the specs that are not keys are
+/// typed as they are.
+pub fn encode_keys<S: AsRef<str>>(specs: &[S], modes: &TerminalModes) -> Vec<u8> {
+    let mut data = Vec::new();
+    for spec in specs {
+        match Key::parse(spec.as_ref()).and_then(|key| key.encode(modes)) {
+            Some(sequence) => data.extend(sequence),
+            None => data.extend_from_slice(spec.as_ref().as_bytes()),
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(spec: &str) -> Vec<u8> {
+        Key::parse(spec)
+            .unwrap()
+            .encode(&TerminalModes::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn keys_are_encoded_with_their_modifiers() {
+        assert_eq!(encode("C-c"), b"\x03");
+        assert_eq!(encode("^C"), b"\x03");
+        assert_eq!(encode("C-Space"), b"\x00");
+        assert_eq!(encode("M-x"), b"\x1bx");
+        assert_eq!(encode("q"), b"q");
+        assert_eq!(encode("Enter"), b"\r");
+        assert_eq!(encode("escape"), b"\x1b");
+        assert_eq!(encode("BSpace"), b"\x7f");
+        assert_eq!(encode("S-Tab"), b"\x1b[Z");
+        assert_eq!(encode("F1"), b"\x1bOP");
+        assert_eq!(encode("F5"), b"\x1b[15~");
+        assert_eq!(encode("F12"), b"\x1b[24~");
+        assert_eq!(encode("C-F5"), b"\x1b[15;5~");
+        assert_eq!(encode("PageUp"), b"\x1b[5~");
+        assert_eq!(encode("C-Up"), b"\x1b[1;5A");
+        assert_eq!(encode("M-S-Left"), b"\x1b[1;4D");
+    }
+
+    #[test]
+    fn text_is_not_a_key() {
+        assert_eq!(Key::parse("hello"), None);
+        assert_eq!(Key::parse("F13"), None);
+        assert_eq!(Key::parse(""), None);
+        assert_eq!(Key::parse("C-"), None);
+        assert_eq!(encode("-"), b"-");
+        assert_eq!(
+            encode_keys(&["ls -l", "Enter"], &TerminalModes::default()),
+            b"ls -l\r"
+        );
+    }
+
+    #[test]
+    fn function_keys_go_from_f1_to_f12() {
+        assert_eq!(Key::parse("F0"), None);
+        assert_eq!(Key::parse("F13"), None);
+        assert_eq!(KeyCode::function(0), None);
+        assert_eq!(KeyCode::function(13), None);
+        assert_eq!(KeyCode::function(12), Some(KeyCode::F(12)));
+        // Built without the parser, they have no sequence
+        for number in [0, 13, 255] {
+            let key = Key {
+                code: KeyCode::F(number),
+                ctrl: false,
+                alt: false,
+                shift: false,
+            };
+            assert_eq!(key.encode(&TerminalModes::default()), None);
+        }
+    }
+
+    #[test]
+    fn cursor_keys_follow_the_application_cursor_mode() {
+        let mut modes = TerminalModes::default();
+        let up = Key::parse("Up").unwrap();
+        assert_eq!(up.encode(&modes).unwrap(), b"\x1b[A");
+
+        // Split between two reads, with other modes
+        modes.update(b"vim\x1b[?1049;");
+        modes.update(b"1h\x1b=");
+        assert!(modes.application_cursor);
+        assert_eq!(up.encode(&modes).unwrap(), b"\x1bOA");
+        assert_eq!(
+            Key::parse("C-Up").unwrap().encode(&modes).unwrap(),
+            b"\x1b[1;5A"
+        );
+
+        modes.update(b"\x1b[1l\x1b[21l");
+        assert!(modes.application_cursor);
+        modes.update(b"\x1b[?1l");
+        assert!(!modes.application_cursor);
+
+        modes.update(b"\x1b[?1h\x1bc");
+        assert!(!modes.application_cursor);
+    }
+
+    #[test]
+    fn bracketed_paste_mode_is_followed() {
+        let mut modes = TerminalModes::default();
+        modes.update(b"\x1b[?2004h$ ");
+        assert!(modes.bracketed_paste);
+        assert!(!modes.application_cursor);
+        modes.update(b"ls\r\n\x1b[?20");
+        assert!(modes.bracketed_paste);
+        modes.update(b"04l");
+        assert!(!modes.bracketed_paste);
+    }
+}
@@ -3,8 +3,19 @@ extern crate crossbeam_channel;
 extern crate errno;
 extern crate libc;
 extern crate nix;
+extern crate serde;
+extern crate serde_json;
 extern crate signal_hook;
 extern crate termion;
 
+pub mod client;
+pub mod config;
+pub mod endpoint;
+pub mod integration;
+pub mod keys;
 pub mod parterm;
+pub mod protocol;
+pub mod quote;
+pub mod recording;
+pub mod screen;
 pub mod shell;
@@ -1,92 +1,867 @@
-use anyhow::Result;
-use clap::{Arg, ArgAction, Command};
+use anyhow::{bail, Result};
+use chrono::{Local, TimeZone};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use flexi_logger::FileSpec;
 use log::info;
+use parterm::client::{list_servers, Client, ServerState};
+use parterm::config::{project_name, Config, CwdMode};
+use parterm::endpoint;
+use parterm::keys::{Key, TerminalModes};
+use parterm::parterm::ServerBuilder;
+use parterm::protocol::{BusyPolicy, InterruptTimeouts, RunCommand};
+use signal_hook::consts::SIGWINCH;
+use signal_hook::iterator::Signals;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use termion::raw::IntoRawMode;
 
+/// Name of the connection outside of the projects
 static DEFAULT_NAME: &str = "default";
+/// Set for the detached server started by `server --detached`
+static DETACHED_SERVER: &str = "PARTERM_DETACHED_SERVER";
 
 fn main() -> Result<()> {
-    flexi_logger::Logger::try_with_env()
-        .unwrap()
-        .start()
-        .unwrap();
+    let config = match std::env::current_dir()
+        .map_err(anyhow::Error::from)
+        .and_then(|dir| Config::load(&dir))
+    {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(2);
+        }
+    };
+    let mut logger =
+        flexi_logger::Logger::try_with_env_or_str(config.log.as_deref().unwrap_or("")).unwrap();
+    if let Some(dir) = &config.log_dir {
+        logger = logger.log_to_file(FileSpec::default().directory(dir));
+    }
+    logger.start().unwrap();
+    // Also seen by the shells of the servers, running clients too
+    if let Some(dir) = &config.socket_dir {
+        if std::env::var_os(endpoint::SOCKET_DIR_VAR).is_none() {
+            std::env::set_var(endpoint::SOCKET_DIR_VAR, dir);
+        }
+    }
 
     let matches = Command::new("parterm")
         .version("0.1")
         .author("Razvan Rotari <razvanrotari@posteo.net>")
         .about("Remote control for your terminal")
         .subcommand_required(true)
+        .arg(
+            Arg::new("shared-dir")
+                .help("Directory of the sockets shared with other users, writable by its owner only")
+                .long("shared-dir")
+                .global(true)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
         .subcommand(
             Command::new("client")
                 .about("")
                 .arg(
                     Arg::new("cmd")
-                        .help("<command>")
-                        .help("Command to run by the server")
+                        .help("Command to run by the server, a single argument is shell code")
                         .required(true)
-                        .action(ArgAction::Set)
+                        .num_args(1..)
+                        .action(ArgAction::Append)
                         .last(true),
                 )
                 .arg(
-                    Arg::new("name")
-                        .help("Name of the connection")
-                        .short('n')
-                        .long("name")
+                    Arg::new("cwd")
+                        .help("Directory to run the command in, defaults to the current directory")
+                        .short('C')
+                        .long("cwd")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("shell-cwd")
+                        .help("Run the command in the current directory of the server shell")
+                        .long("shell-cwd")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("cwd"),
+                )
+                .arg(
+                    Arg::new("cd")
+                        .help("Change the directory of the server shell instead of using a subshell")
+                        .long("cd")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("shell-cwd"),
+                )
+                .arg(
+                    Arg::new("env")
+                        .help("Environment variable set for the command, as NAME=VALUE")
+                        .short('e')
+                        .long("env")
+                        .action(ArgAction::Append),
+                )
+                .arg(name_arg())
+                .arg(
+                    Arg::new("wait")
+                        .help("Wait for the command to finish and exit with its status")
+                        .short('w')
+                        .long("wait")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tee")
+                        .help("Also print the command output here, implies --wait")
+                        .short('t')
+                        .long("tee")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("paste")
+                        .help("Type the command as a bracketed paste, multi-line commands are then read as a whole")
+                        .short('p')
+                        .long("paste")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("if-busy")
+                        .help("What to do when a program is running in the server shell")
+                        .long("if-busy")
+                        .action(ArgAction::Set)
+                        .value_parser(["queue", "reject", "interrupt"]),
+                )
+                .arg(
+                    Arg::new("replace")
+                        .help("Stop the running program before running the command, same as --if-busy interrupt")
+                        .long("replace")
+                        .visible_alias("interrupt")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("term-after")
+                        .help("Seconds before the interrupted program gets SIGTERM, 2 by default")
+                        .long("term-after")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("kill-after")
+                        .help("Seconds after SIGTERM before the interrupted program gets SIGKILL, 2 by default")
+                        .long("kill-after")
                         .action(ArgAction::Set)
-                        .default_value(DEFAULT_NAME),
+                        .value_parser(clap::value_parser!(f64)),
                 ),
         )
         .subcommand(
             Command::new("server")
                 .about("")
-                .arg(
-                    Arg::new("name")
-                        .help("Name of the connection")
-                        .short('n')
-                        .long("name")
-                        .action(ArgAction::Set)
-                        .default_value(DEFAULT_NAME),
-                )
+                .arg(name_arg())
                 .arg(
                     Arg::new("cmd")
                         .help("Command to be executed after the server starts")
                         .short('c')
                         .action(ArgAction::Set)
                         .long("command"),
+                )
+                .arg(
+                    Arg::new("allow-uid")
+                        .help("Also accept the clients run by this user id, needs --shared-dir")
+                        .long("allow-uid")
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("scrollback")
+                        .help("Number of lines scrolled off the screen kept for capture")
+                        .long("scrollback")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("record")
+                        .help("Record the terminal in the file, in the asciicast v2 format")
+                        .long("record")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("detached")
+                        .help("Run in the background without tty, use attach to see the terminal")
+                        .short('d')
+                        .long("detached")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-shell-integration")
+                        .help("Do not set the shell up to print OSC 133 prompt markers")
+                        .long("no-shell-integration")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("send-keys")
+                .about("Type keys in the server terminal")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("literal")
+                        .help("Type the arguments as they are, without looking for key names")
+                        .short('l')
+                        .long("literal")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("keys")
+                        .help("Keys like q, C-c, Enter, Escape, Up or F5, other arguments are typed as they are")
+                        .required(true)
+                        .num_args(1..)
+                        .allow_hyphen_values(true)
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("capture")
+                .about("Print what the server terminal shows")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("history")
+                        .help("Also print up to this many lines scrolled off the screen")
+                        .short('H')
+                        .long("history")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("Plain text, text with the ANSI attributes or JSON")
+                        .short('f')
+                        .long("format")
+                        .action(ArgAction::Set)
+                        .value_parser(["text", "ansi", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("expect")
+                .about("Wait for the server terminal to print text matching a pattern")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("timeout")
+                        .help("Seconds to wait before exiting with 1, waits forever by default")
+                        .short('t')
+                        .long("timeout")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("recent")
+                        .help("Also look in this many lines already printed")
+                        .short('r')
+                        .long("recent")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("quiet")
+                        .help("Do not print the matched text")
+                        .short('q')
+                        .long("quiet")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("pattern")
+                        .help("Regular expression matched against the output without its escape sequences")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("record")
+                .about("Start or stop recording the server terminal in the asciicast v2 format")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("stop")
+                        .help("Stop the recording")
+                        .long("stop")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("file"),
+                )
+                .arg(
+                    Arg::new("file")
+                        .help("File written by the server")
+                        .required_unless_present("stop"),
+                ),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Run a task defined in the configuration of the server")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("wait")
+                        .help("Wait for the task to finish and exit with its status")
+                        .short('w')
+                        .long("wait")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tee")
+                        .help("Also print the task output here, implies --wait")
+                        .short('t')
+                        .long("tee")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("list")
+                        .help("List the tasks of the server")
+                        .short('l')
+                        .long("list")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("task")
+                        .help("Name of the task")
+                        .required_unless_present("list"),
+                ),
+        )
+        .subcommand(
+            Command::new("attach")
+                .about("Follow the server terminal, typing in it unless read only. Ctrl-] detaches")
+                .arg(
+                    Arg::new("read-only")
+                        .help("Only watch the terminal, Ctrl-C also detaches")
+                        .short('r')
+                        .long("read-only")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    // Given as an argument, as in `parterm attach build`
+                    name_arg().short(None).long(None),
                 ),
         )
+        .subcommand(
+            Command::new("list").about("List the servers").arg(
+                Arg::new("clean")
+                    .help("Remove the sockets left behind by crashed servers")
+                    .long("clean")
+                    .action(ArgAction::SetTrue),
+            ),
+        )
         .get_matches();
+    if let Some(dir) = matches.get_one::<PathBuf>("shared-dir") {
+        std::env::set_var(endpoint::SHARED_DIR_VAR, dir);
+    }
 
     if let Some(client_sub) = matches.subcommand_matches("client") {
         info!("Client");
-        if let Some(val) = client_sub.get_one::<String>("cmd") {
-            let path = format!(
-                "parterm_{}.pipe",
-                client_sub
-                    .get_one::<String>("name")
-                    .unwrap_or(&DEFAULT_NAME.to_string())
-            );
-            if let Err(err) = parterm::parterm::client(val.to_owned() + "\n", &path) {
-                info!("Error {}", err);
+        if let Some(argv) = client_sub.get_many::<String>("cmd") {
+            let argv: Vec<String> = argv.cloned().collect();
+            let name = &session_name(client_sub, &config);
+            let policy = match client_sub.get_one::<String>("if-busy").map(|x| x.as_str()) {
+                _ if client_sub.get_flag("replace") => BusyPolicy::Interrupt,
+                Some("queue") => BusyPolicy::Queue,
+                Some("reject") => BusyPolicy::Reject,
+                Some("interrupt") => BusyPolicy::Interrupt,
+                _ => config.client.if_busy.unwrap_or_default(),
+            };
+            let millis = |arg, configured: Option<f64>| {
+                let seconds = client_sub
+                    .get_one::<f64>(arg)
+                    .copied()
+                    .or(configured)
+                    .unwrap_or(2.0);
+                (seconds.max(0.0) * 1000.0) as u64
+            };
+            let mut env = Vec::new();
+            for var in client_sub.get_many::<String>("env").into_iter().flatten() {
+                match var.split_once('=') {
+                    Some((name, value)) => env.push((name.to_string(), value.to_string())),
+                    None => {
+                        eprintln!("Error: {:?} is not NAME=VALUE", var);
+                        std::process::exit(2);
+                    }
+                }
+            }
+            let dir = client_sub.get_one::<String>("cwd");
+            let cd = client_sub.get_flag("cd");
+            let shell_cwd = client_sub.get_flag("shell-cwd")
+                || (dir.is_none() && !cd && config.client.cwd == Some(CwdMode::Shell));
+            let cwd = if shell_cwd {
+                None
+            } else {
+                // Relative to the client directory, not the shell one
+                match std::env::current_dir() {
+                    Ok(current_dir) => match dir {
+                        Some(dir) => Some(current_dir.join(dir)),
+                        None => Some(current_dir),
+                    },
+                    Err(err) => {
+                        eprintln!("Error: unable to get the current directory: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            };
+            // Only a directory asked for fails when the shell can not go there
+            let cwd = match cwd {
+                Some(cwd) if dir.is_none() && !cd => {
+                    match Client::connect(name).and_then(|client| client.can_run_in(&cwd)) {
+                        Ok(true) => Some(cwd),
+                        Ok(false) => {
+                            eprintln!(
+                                "Warning: the server shell can not be moved to {}, \
+                                 the command runs in its current directory",
+                                cwd.display()
+                            );
+                            None
+                        }
+                        Err(err) => {
+                            eprintln!("Error: {}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                cwd => cwd,
+            };
+            let command = RunCommand {
+                // A single argument is the command line typed by the user
+                raw: argv.len() == 1,
+                argv,
+                cwd,
+                cd,
+                env,
+                paste: client_sub.get_flag("paste") || config.client.paste.unwrap_or(false),
+                policy,
+                timeouts: InterruptTimeouts {
+                    term_after_ms: millis("term-after", config.client.term_after),
+                    kill_after_ms: millis("kill-after", config.client.kill_after),
+                },
+                ..Default::default()
+            };
+            if client_sub.get_flag("wait") || client_sub.get_flag("tee") {
+                match run(name, command, client_sub.get_flag("tee")) {
+                    Ok(status) => std::process::exit(status),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Err(err) = Client::connect(name).and_then(|client| client.execute(command)) {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
             }
         }
         return Ok(());
     }
     if let Some(server_sub) = matches.subcommand_matches("server") {
         info!("server");
-        let path = format!(
-            "parterm_{}.pipe",
-            server_sub
-                .get_one::<String>("name")
-                .unwrap_or(&DEFAULT_NAME.to_string())
-        );
-        if let Err(err) = parterm::parterm::server(
-            path,
-            server_sub.get_one::<String>("cmd").map(|x| x.as_str()),
-        ) {
-            info!("Error {}", err);
+        let name = &session_name(server_sub, &config);
+        let settings = config.server(name);
+        let shell_integration = !server_sub.get_flag("no-shell-integration")
+            && settings.shell_integration.unwrap_or(true);
+        let mut builder = ServerBuilder::new(name)
+            .handle_signals(true)
+            .shell_integration(shell_integration);
+        if let Some(shell) = &settings.shell {
+            builder = builder.program(shell);
+        }
+        if let Some(cmd) = server_sub
+            .get_one::<String>("cmd")
+            .or(settings.command.as_ref())
+        {
+            builder = builder.startup_command(cmd);
+        }
+        if let Some(lines) = server_sub
+            .get_one::<usize>("scrollback")
+            .or(settings.scrollback.as_ref())
+        {
+            builder = builder.scrollback(*lines);
+        }
+        match server_sub.get_one::<String>("record") {
+            Some(path) => builder = builder.record(recording_path(&config, path)),
+            None if settings.record == Some(true) => {
+                let file = format!("{}-{}.cast", name, Local::now().format("%Y%m%d-%H%M%S"));
+                builder = builder.record(recording_path(&config, &file));
+            }
+            None => {}
+        }
+        match config.tasks() {
+            Ok(tasks) => {
+                for (task, command) in tasks {
+                    builder = builder.task(&task, command);
+                }
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(2);
+            }
+        }
+        let allowed_uids: Vec<u32> = server_sub
+            .get_many::<u32>("allow-uid")
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        // The private directory of the sockets can not be reached by others
+        if !allowed_uids.is_empty() && std::env::var_os(endpoint::SHARED_DIR_VAR).is_none() {
+            eprintln!("Error: --allow-uid needs --shared-dir");
+            std::process::exit(2);
+        }
+        for uid in allowed_uids {
+            builder = builder.allow_uid(uid);
+        }
+        if server_sub.get_flag("detached") {
+            if std::env::var_os(DETACHED_SERVER).is_none() {
+                if let Err(err) = spawn_detached(name) {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            // The shell would start its own detached servers in the foreground
+            builder = builder.detached(true).env_remove(DETACHED_SERVER);
+        }
+        match builder.run().and_then(|server| server.wait()) {
+            Ok(status) => std::process::exit(status),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(keys_sub) = matches.subcommand_matches("send-keys") {
+        info!("send-keys");
+        let name = &session_name(keys_sub, &config);
+        let keys: Vec<String> = keys_sub
+            .get_many::<String>("keys")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let sent = Client::connect(name).and_then(|client| {
+            if keys_sub.get_flag("literal") {
+                client.send_keys(keys.concat().as_bytes())
+            } else {
+                client.send_named_keys(keys)
+            }
+        });
+        if let Err(err) = sent {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(capture_sub) = matches.subcommand_matches("capture") {
+        info!("capture");
+        let name = &session_name(capture_sub, &config);
+        let history = capture_sub.get_one::<usize>("history").copied();
+        let format = capture_sub.get_one::<String>("format").map(|x| x.as_str());
+        if let Err(err) = capture(name, history.unwrap_or_default(), format) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(expect_sub) = matches.subcommand_matches("expect") {
+        info!("expect");
+        let name = &session_name(expect_sub, &config);
+        let pattern = expect_sub
+            .get_one::<String>("pattern")
+            .map(|x| x.as_str())
+            .unwrap_or_default();
+        let timeout = expect_sub
+            .get_one::<f64>("timeout")
+            .map(|seconds| Duration::from_secs_f64(seconds.max(0.0)));
+        let recent = expect_sub
+            .get_one::<usize>("recent")
+            .copied()
+            .unwrap_or_default();
+        match Client::connect(name).and_then(|client| client.expect(pattern, timeout, recent)) {
+            Ok(Some(text)) => {
+                if !expect_sub.get_flag("quiet") {
+                    println!("{}", text);
+                }
+            }
+            Ok(None) => {
+                eprintln!("Timed out waiting for {:?}", pattern);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(record_sub) = matches.subcommand_matches("record") {
+        info!("record");
+        let name = &session_name(record_sub, &config);
+        let file = record_sub.get_one::<String>("file");
+        let recorded = Client::connect(name).and_then(|client| match file {
+            Some(file) => client.start_recording(recording_path(&config, file)),
+            None => client.stop_recording(),
+        });
+        if let Err(err) = recorded {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
         return Ok(());
     }
+    if let Some(run_sub) = matches.subcommand_matches("run") {
+        info!("run");
+        let name = &session_name(run_sub, &config);
+        let result = match run_sub.get_one::<String>("task") {
+            _ if run_sub.get_flag("list") => list_tasks(name).map(|()| 0),
+            Some(task) if run_sub.get_flag("wait") || run_sub.get_flag("tee") => {
+                run_task(name, task, run_sub.get_flag("tee"))
+            }
+            Some(task) => Client::connect(name)
+                .and_then(|client| client.execute_task(task))
+                .map(|()| 0),
+            None => unreachable!("the task is required"),
+        };
+        match result {
+            Ok(status) => std::process::exit(status),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(attach_sub) = matches.subcommand_matches("attach") {
+        info!("attach");
+        let name = &session_name(attach_sub, &config);
+        let detach = config.keys.detach.as_deref().unwrap_or("C-]");
+        let detach = match Key::parse(detach).and_then(|key| key.encode(&TerminalModes::default()))
+        {
+            Some(sequence) => sequence,
+            None => {
+                eprintln!("Error: unknown detach key {:?}", detach);
+                std::process::exit(2);
+            }
+        };
+        return attach(name, attach_sub.get_flag("read-only"), &detach);
+    }
+    if let Some(list_sub) = matches.subcommand_matches("list") {
+        info!("list");
+        return list(list_sub.get_flag("clean"));
+    }
 
     Ok(())
 }
+
+/// Runs the command and waits for it, printing its output if tee is set.
+/// Returns the command exit status.
+fn run(name: &str, command: RunCommand, tee: bool) -> Result<i32> {
+    let command = RunCommand { tee, ..command };
+    let mut handle = Client::connect(name)?.run_command(command)?;
+    let mut stdout = std::io::stdout();
+    for output in handle.output_stream() {
+        stdout.write_all(&output?)?;
+        stdout.flush()?;
+    }
+    handle.wait()
+}
+
+/// Runs the task and waits for it, printing its output if tee is set.
+/// Returns the task exit status.
+fn run_task(name: &str, task: &str, tee: bool) -> Result<i32> {
+    let mut handle = Client::connect(name)?.run_task(task, tee)?;
+    let mut stdout = std::io::stdout();
+    for output in handle.output_stream() {
+        stdout.write_all(&output?)?;
+        stdout.flush()?;
+    }
+    handle.wait()
+}
+
+/// Prints the names of the tasks of the server.
+fn list_tasks(name: &str) -> Result<()> {
+    for task in Client::connect(name)?.status()?.tasks {
+        println!("{}", task);
+    }
+    Ok(())
+}
+
+/// Prints the server screen and the end of its scrollback in the given format.
+fn capture(name: &str, history: usize, format: Option<&str>) -> Result<()> {
+    let capture = Client::connect(name)?.capture(history)?;
+    let output = match format {
+        Some("json") => serde_json::to_string(&capture)? + "\n",
+        Some("ansi") => capture.to_ansi(),
+        _ => capture.to_text(),
+    };
+    std::io::stdout().write_all(output.as_bytes())?;
+    Ok(())
+}
+
+/// The `--name` option of the commands talking to a server.
+fn name_arg() -> Arg {
+    Arg::new("name")
+        .help("Name of the connection, defaults to the configured one or to the project one")
+        .short('n')
+        .long("name")
+        .action(ArgAction::Set)
+}
+
+/// Name of the connection given to the command, the configured one or the
+/// one of the project of the current directory.
+fn session_name(matches: &ArgMatches, config: &Config) -> String {
+    matches
+        .get_one::<String>("name")
+        .or(config.name.as_ref())
+        .cloned()
+        .or_else(|| project_name(&std::env::current_dir().ok()?))
+        .unwrap_or_else(|| DEFAULT_NAME.to_string())
+}
+
+/// Puts the relative recording paths in the configured directory.
+fn recording_path(config: &Config, path: &str) -> PathBuf {
+    match &config.record.dir {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Starts this server again in the background, in its own session, and
+/// returns once it accepts clients.
+fn spawn_detached(name: &str) -> Result<()> {
+    if Client::connect(name).is_ok() {
+        bail!("A server named {} is already running", name);
+    }
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_SERVER, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // Leaves the session of this terminal to survive it
+    unsafe {
+        command.pre_exec(|| nix::unistd::setsid().map(drop).map_err(io::Error::from));
+    }
+    let mut server = command.spawn()?;
+    loop {
+        if let Some(status) = server.try_wait()? {
+            bail!("The server exited with {}", status);
+        }
+        if Client::connect(name).is_ok() {
+            println!(
+                "Started {} in the background, attach with: parterm attach {}",
+                name, name
+            );
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Shows the server terminal in this one until the detach key is typed or
+/// the server shell exits.
+fn attach(name: &str, read_only: bool, detach_key: &[u8]) -> Result<()> {
+    const CTRL_C: u8 = 0x03;
+
+    let client = Client::connect(name)?;
+    // A detached server takes the size of the terminal typing in it
+    if !read_only && client.status()?.detached {
+        let (width, height) = termion::terminal_size()?;
+        client.resize(width, height)?;
+        let client = client.clone();
+        let mut signals = Signals::new([SIGWINCH])?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if let Ok((width, height)) = termion::terminal_size() {
+                    let _ = client.resize(width, height);
+                }
+            }
+        });
+    }
+    let attachment = client.attach(read_only)?;
+    let input = attachment.try_clone()?;
+    let mut stdout = std::io::stdout().into_raw_mode()?;
+    let detach_key = detach_key.to_vec();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buffer = [0; 1024];
+        while let Ok(read @ 1..) = stdin.read(&mut buffer) {
+            let data = &buffer[..read];
+            let found = |key: &[u8]| data.windows(key.len()).position(|keys| keys == key);
+            let detach = found(&detach_key).or_else(|| found(&[CTRL_C]).filter(|_| read_only));
+            if !read_only {
+                let keys = &data[..detach.unwrap_or(read)];
+                if !keys.is_empty() && input.send_keys(keys).is_err() {
+                    break;
+                }
+            }
+            if detach.is_some() {
+                break;
+            }
+        }
+        let _ = input.detach();
+    });
+
+    let mut result = Ok(());
+    for output in attachment {
+        match output {
+            Ok(data) => {
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    // Leaves the modes the server terminal may have set
+    write!(stdout, "\x1b[0m\x1b[?1l\x1b[?2004l\r\n")?;
+    stdout.flush()?;
+    drop(stdout);
+    result
+}
+
+/// Prints a line per server, removing the stale sockets if clean is set.
+fn list(clean: bool) -> Result<()> {
+    println!(
+        "{:<16} {:>8} {:<16} {:<19} {:<8} {:<10} CWD",
+        "NAME", "PID", "SHELL", "STARTED", "STATE", "QUEUE"
+    );
+    for (name, state) in list_servers()? {
+        match state {
+            ServerState::Running(info) => {
+                let started = Local
+                    .timestamp_opt(info.started as i64, 0)
+                    .single()
+                    .map(|started| started.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                let cwd = info
+                    .cwd
+                    .map(|cwd| cwd.display().to_string())
+                    .unwrap_or_default();
+                // Commands waiting long for the shell show it never gets idle
+                let queue = match info.waiting_ms {
+                    Some(waiting_ms) => format!("{} ({}s)", info.queued, waiting_ms / 1000),
+                    None => info.queued.to_string(),
+                };
+                println!(
+                    "{:<16} {:>8} {:<16} {:<19} {:<8} {:<10} {}",
+                    name,
+                    info.pid,
+                    info.shell,
+                    started,
+                    if info.busy { "busy" } else { "idle" },
+                    queue,
+                    cwd
+                );
+            }
+            ServerState::Stale if clean => {
+                parterm::endpoint::delete(&name)?;
+                println!("{:<16} {:>8} {:<16} {:<19} removed", name, "", "", "");
+            }
+            ServerState::Stale => println!("{:<16} {:>8} {:<16} {:<19} stale", name, "", "", ""),
+            ServerState::Unreachable(err) => {
+                println!(
+                    "{:<16} {:>8} {:<16} {:<19} error    {}",
+                    name, "", "", "", err
+                )
+            }
+        }
+    }
+    Ok(())
+}
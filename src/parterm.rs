@@ -1,23 +1,292 @@
-use crate::shell::pty::Pty;
-use crate::shell::tui::get_terminal_size;
+use crate::endpoint;
+use crate::integration::{self, Marker, ShellKind, MARKER_OSC};
+use crate::keys::{self, TerminalModes};
+use crate::protocol::{
+    read_frame, server_handshake, write_frame, BusyPolicy, ErrorKind, InterruptTimeouts, Reply,
+    Request, RunCommand, ServerInfo, PROTOCOL_VERSION,
+};
+use crate::quote::{self, Dialect};
+use crate::recording::Recorder;
+use crate::screen::{PlainText, Screen, DEFAULT_SCROLLBACK};
+use crate::shell::pty::{Pty, PtyError};
+use crate::shell::tui::{get_terminal_size, Size};
 use crate::shell::util::get_shell;
 use anyhow::{bail, Result};
 use crossbeam_channel::select;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
 use libc::c_int;
-use log::{debug, error};
-use nix::sys::stat;
-use nix::unistd;
-use std::env::temp_dir;
-use std::fs::{File, OpenOptions};
+use log::{debug, error, warn};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::fs::File;
 use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic;
-use std::path;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use termion::get_tty;
 use termion::raw::IntoRawMode;
 
+/// OSC sequence printed by the shell after a waited command finished, for
+/// shells without integration.
+///
+/// The full sequence is `ESC ] 6973 ; <id> ; <status> BEL`.
+const STATUS_OSC: &[u8] = b"\x1b]6973;";
+
+/// Sequences around the text pasted in a terminal in bracketed paste mode.
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Start of every OSC sequence.
+const OSC: &[u8] = b"\x1b]";
+
+/// Longest status or marker sequence body, anything longer is plain output.
+const MAX_SEQUENCE_LEN: usize = 32;
+
+/// How long the first prompt of an integrated shell is waited for before
+/// running commands without it.
+const FIRST_PROMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the end of a prompt is waited for before the shell is found idle
+/// from the foreground process, when a prompt hook removed the marker.
+const MISSING_INPUT_MARK_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a prompt is waited for after a command started or finished
+/// before the shell is found idle from the foreground process, when a broken
+/// prompt hook stopped the markers.
+const MISSING_PROMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Replies not sent yet to a client waiting for its command, beyond which
+/// the client is left behind.
+const WAITER_BACKLOG: usize = 1024;
+
+/// A client waiting for its command to finish.
+struct Waiter {
+    /// Replies written to the client by its own thread
+    sender: Sender<Reply>,
+    /// Send the command output to the client
+    tee: bool,
+    /// The command prints its status itself, its echo is found from it
+    wrapped: bool,
+    /// The shell echo of the command line was skipped
+    echo_skipped: bool,
+    /// End of the output read before the echo was skipped, where the end of
+    /// the echo may start
+    echo_tail: Vec<u8>,
+}
+
+/// What the server knows about the shell from its markers and its output.
+#[derive(Default)]
+struct ShellState {
+    /// The shell prints the OSC 133 prompt markers
+    integrated: bool,
+    /// Until when the first prompt of a shell set up to print the markers
+    /// is waited for
+    first_prompt_deadline: Option<Instant>,
+    /// The shell shows its prompt, known only when integrated
+    at_prompt: bool,
+    /// When the last prompt started, until a command is typed at it
+    prompt_started: Option<Instant>,
+    /// The shell marks the end of its prompts, where commands can be typed
+    marks_input: bool,
+    /// When a command started or finished, until a prompt follows
+    prompt_missing_since: Option<Instant>,
+    /// Id of the waited command typed at the last prompt, until it finished
+    running: Option<u64>,
+    /// Modes set by the program running in the terminal
+    modes: TerminalModes,
+}
+
+type SharedState = Arc<Mutex<ShellState>>;
+
+impl Waiter {
+    /// Starts the thread writing the replies to the client, ending once the
+    /// waiter is dropped.
+    fn new(id: u64, stream: UnixStream, tee: bool) -> Waiter {
+        let (sender, receiver) = crossbeam_channel::bounded(WAITER_BACKLOG);
+        let mut stream = stream;
+        spawn_with_name("WaiterOutput", move || {
+            for reply in receiver {
+                if let Err(err) = write_frame(&mut stream, &reply) {
+                    error!("Client {} went away: {:?}", id, err);
+                    return;
+                }
+            }
+        });
+        Waiter {
+            sender,
+            tee,
+            wrapped: false,
+            echo_skipped: false,
+            echo_tail: Vec::new(),
+        }
+    }
+}
+
+/// Commands waiting for their exit status, by id.
+type Waiters = Arc<Mutex<HashMap<u64, Waiter>>>;
+
+/// Longest output kept to match an expected pattern, the oldest is dropped.
+const MAX_EXPECTED_TEXT: usize = 64 * 1024;
+
+/// A client waiting for the shell to print text matching a pattern.
+struct Expectation {
+    pattern: Regex,
+    /// Output printed since the client started waiting, without the sequences
+    text: String,
+    /// Gets the matched text
+    sender: Sender<String>,
+}
+
+/// Expected output, by client id.
+type Expectations = Arc<Mutex<HashMap<u64, Expectation>>>;
+
+/// The recording of the terminal, if one was started.
+type Recording = Arc<Mutex<Option<Recorder>>>;
+
+/// Output not sent yet to a client following the terminal, beyond which the
+/// client is left behind.
+const VIEWER_BACKLOG: usize = 1024;
+
+/// Clients following the terminal output, by id.
+type Viewers = Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>;
+
+/// How long the shell has to exit once hung up, before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the output thread checks if the shell exited.
+const OUTPUT_POLL: Duration = Duration::from_millis(100);
+
+/// How long the output is still read once the shell exited, when programs
+/// left in the background keep printing.
+const EXIT_DRAIN: Duration = Duration::from_millis(500);
+
+/// How often a queued command checks if the shell is idle.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+/// How long a command waits for the shell before it is logged.
+const STALLED_QUEUE_WARNING: Duration = Duration::from_secs(60);
+
+/// Time given to the shell to start a command before checking it is idle again.
+const COMMAND_SETTLE: Duration = Duration::from_millis(100);
+
+/// A command waiting for the shell to be idle.
+struct QueuedCommand {
+    /// The command line, ending with a newline
+    cmd: Vec<u8>,
+    /// Id of the client waiting for the command, if any
+    waiter: Option<u64>,
+    /// Type it as a bracketed paste when the shell enabled them
+    paste: bool,
+}
+
+/// Commands waiting for the shell to be idle to be typed.
+#[derive(Clone)]
+struct CommandQueue {
+    sender: Sender<QueuedCommand>,
+    progress: Arc<QueueProgress>,
+    state: SharedState,
+}
+
+/// How far the queue is, shown in the server status.
+#[derive(Default)]
+struct QueueProgress {
+    /// Commands queued and not typed yet
+    pending: AtomicUsize,
+    /// Since when the next command waits for the shell to be idle
+    waiting_since: Mutex<Option<Instant>>,
+}
+
+/// What the client handlers share with the rest of the server.
+#[derive(Clone)]
+struct Context {
+    name: String,
+    /// Program run in the terminal
+    shell: String,
+    cmd_sender: Sender<Vec<u8>>,
+    queue: CommandQueue,
+    pty: Arc<Pty>,
+    waiters: Waiters,
+    expectations: Expectations,
+    viewers: Viewers,
+    /// What the terminal shows
+    screen: Arc<Mutex<Screen>>,
+    recording: Recording,
+    /// Start time in seconds since the Unix epoch
+    started: u64,
+    /// Users allowed to connect besides the one running the server
+    allowed_uids: Vec<u32>,
+    /// The server runs without tty
+    detached: bool,
+    /// Commands run by name
+    tasks: Arc<HashMap<String, RunCommand>>,
+}
+
+/// Configuration of a server, started with [`ServerBuilder::run`].
+///
+/// # Example
+///
+/// ```no_run
+/// # use parterm::parterm::ServerBuilder;
+/// # fn main() -> anyhow::Result<()> {
+/// let server = ServerBuilder::new("build")
+///     .program("/bin/bash")
+///     .args(["--norc"])
+///     .cwd("/tmp")
+///     .output(std::io::sink())
+///     .input(std::io::empty())
+///     .run()?;
+/// server.shutdown();
+/// let status = server.wait()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerBuilder {
+    name: String,
+    program: Option<String>,
+    args: Vec<String>,
+    /// Variables set, or removed when without value, in order
+    env: Vec<(String, Option<String>)>,
+    cwd: Option<PathBuf>,
+    startup_command: Option<String>,
+    allowed_uids: Vec<u32>,
+    shared_dir: Option<PathBuf>,
+    input: Option<Box<dyn Read + Send>>,
+    output: Option<Box<dyn Write + Send>>,
+    size: Option<Size>,
+    handle_signals: bool,
+    shell_integration: bool,
+    scrollback: usize,
+    record: Option<PathBuf>,
+    detached: bool,
+    tasks: HashMap<String, RunCommand>,
+}
+
+/// A running server.
+pub struct Server {
+    stopper: Stopper,
+    output_thread: thread::JoinHandle<Result<()>>,
+    shell_thread: thread::JoinHandle<std::io::Result<i32>>,
+}
+
+/// Stops a server, from any thread.
+#[derive(Clone)]
+struct Stopper {
+    name: String,
+    socket: PathBuf,
+    pty: Arc<Pty>,
+    stopped: Arc<AtomicBool>,
+    /// Disconnected once the shell exited
+    exited: Receiver<()>,
+}
+
 fn spawn_with_name<F, T>(name: &str, f: F) -> thread::JoinHandle<T>
 where
     F: FnOnce() -> T,
@@ -43,215 +312,2315 @@ fn notify(signals: &[c_int]) -> Result<crossbeam_channel::Receiver<c_int>> {
     Ok(r)
 }
 
-fn create_pipe(path: &path::PathBuf) -> Result<()> {
-    match unistd::mkfifo(path, stat::Mode::S_IRWXU) {
-        Ok(_) => (),
-        Err(err) => bail!("Error creating fifo: {}", err),
+impl ServerBuilder {
+    /// Starts the configuration of a server listening under the given name.
+    ///
+    /// By default the server runs the user shell in the terminal it was started
+    /// from.
+    pub fn new(name: &str) -> ServerBuilder {
+        ServerBuilder {
+            name: name.to_string(),
+            program: None,
+            args: Vec::new(),
+            env: Vec::new(),
+            cwd: None,
+            startup_command: None,
+            allowed_uids: Vec::new(),
+            shared_dir: None,
+            input: None,
+            output: None,
+            size: None,
+            handle_signals: false,
+            shell_integration: true,
+            scrollback: DEFAULT_SCROLLBACK,
+            record: None,
+            detached: false,
+            tasks: HashMap::new(),
+        }
     }
-    Ok(())
-}
 
-fn get_pipe(name: &str, write: bool) -> Result<File> {
-    let dir = temp_dir();
-    let pipe_file = dir.join(path::PathBuf::from(name));
-    debug!("pipe_file {:?}", pipe_file);
-    if !pipe_file.exists() {
-        if write {
-            bail!("No server open for {} ", name);
-        }
-        create_pipe(&pipe_file)?
+    /// Program run in the terminal instead of the user shell.
+    pub fn program(mut self, program: &str) -> ServerBuilder {
+        self.program = Some(program.to_string());
+        self
     }
-    let mut option = OpenOptions::new();
-    let pipe = option
-        .read(!write)
-        .create(false) // It will crash if is's set to true
-        .write(write)
-        .open(pipe_file)?;
 
-    debug!("Pipe open");
-    Ok(pipe)
-}
+    /// Arguments of the program.
+    pub fn args<I, S>(mut self, args: I) -> ServerBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
 
-fn delete_pipe(name: &str) -> std::io::Result<()> {
-    let dir = temp_dir();
-    let pipe_file = dir.join(path::PathBuf::from(name));
-    debug!("remove pipe_file {:?}", pipe_file);
-    std::fs::remove_file(pipe_file)
-}
+    /// Sets an environment variable of the program.
+    pub fn env(mut self, key: &str, value: &str) -> ServerBuilder {
+        self.env.push((key.to_string(), Some(value.to_string())));
+        self
+    }
 
-pub fn client(value: String, name: &str) -> Result<()> {
-    let mut pipe = get_pipe(name, true)?;
-    let written = pipe.write(value.as_bytes())?;
-    assert_eq!(written, value.as_bytes().len());
-    pipe.sync_all()?;
-    Ok(())
+    /// Removes an environment variable the program would inherit.
+    pub fn env_remove(mut self, key: &str) -> ServerBuilder {
+        self.env.push((key.to_string(), None));
+        self
+    }
+
+    /// Directory the program starts in.
+    pub fn cwd<P: Into<PathBuf>>(mut self, cwd: P) -> ServerBuilder {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Command run once the program started.
+    pub fn startup_command(mut self, command: &str) -> ServerBuilder {
+        self.startup_command = Some(command.to_string());
+        self
+    }
+
+    /// Also accepts the clients run by the given user id.
+    ///
+    /// They can only reach a socket in a [shared directory](Self::shared_dir).
+    pub fn allow_uid(mut self, uid: u32) -> ServerBuilder {
+        self.allowed_uids.push(uid);
+        self
+    }
+
+    /// Listens in the given directory, shared with other users, instead of
+    /// the private one. See [`endpoint`] for its permissions.
+    pub fn shared_dir<P: Into<PathBuf>>(mut self, dir: P) -> ServerBuilder {
+        self.shared_dir = Some(dir.into());
+        self
+    }
+
+    /// Where the keys typed in the terminal come from, instead of the tty.
+    pub fn input<R: Read + Send + 'static>(mut self, input: R) -> ServerBuilder {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Where the terminal output goes, instead of the tty.
+    pub fn output<W: Write + Send + 'static>(mut self, output: W) -> ServerBuilder {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Size of the terminal, defaults to the tty size or to 80x24 without tty.
+    pub fn size(mut self, size: Size) -> ServerBuilder {
+        self.size = Some(size);
+        self
+    }
+
+    /// Follows the tty size on SIGWINCH, shuts down on SIGTERM and SIGHUP and
+    /// forwards SIGINT to the program in the foreground of the terminal.
+    pub fn handle_signals(mut self, handle_signals: bool) -> ServerBuilder {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Sets bash, zsh and fish up to print OSC 133 markers, giving the
+    /// commands boundaries and exit status. On by default, only done when no
+    /// arguments are given to the shell.
+    pub fn shell_integration(mut self, shell_integration: bool) -> ServerBuilder {
+        self.shell_integration = shell_integration;
+        self
+    }
+
+    /// Number of lines scrolled off the screen kept for the captures.
+    pub fn scrollback(mut self, lines: usize) -> ServerBuilder {
+        self.scrollback = lines;
+        self
+    }
+
+    /// Records the terminal in the file, in the asciicast v2 format.
+    pub fn record<P: Into<PathBuf>>(mut self, path: P) -> ServerBuilder {
+        self.record = Some(path.into());
+        self
+    }
+
+    /// Runs without tty, the keys only come from the clients. The terminal
+    /// is 80x24 unless a size is given, until a client resizes it.
+    pub fn detached(mut self, detached: bool) -> ServerBuilder {
+        self.detached = detached;
+        self
+    }
+
+    /// Defines a task, a command the clients run by its name.
+    pub fn task(mut self, name: &str, command: RunCommand) -> ServerBuilder {
+        self.tasks.insert(name.to_string(), command);
+        self
+    }
+
+    /// Starts the server and returns once it accepts clients.
+    pub fn run(self) -> Result<Server> {
+        let socket = match &self.shared_dir {
+            Some(dir) => {
+                endpoint::check_shared_dir(dir)?;
+                endpoint::socket_path(dir, &self.name)?
+            }
+            None => endpoint::endpoint_path(&self.name)?,
+        };
+        // Bound first, to fail before starting anything when the name is taken
+        let listener = endpoint::bind_at(&socket)?;
+        self.start(listener, socket.clone()).inspect_err(|_| {
+            if let Err(err) = std::fs::remove_file(&socket) {
+                warn!("Unable to remove the socket {:?}: {}", socket, err);
+            }
+        })
+    }
+
+    /// Starts the shell and the threads of the server listening on the socket.
+    fn start(mut self, listener: UnixListener, socket: PathBuf) -> Result<Server> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        if self.detached {
+            self.input.get_or_insert_with(|| Box::new(std::io::empty()));
+            self.output.get_or_insert_with(|| Box::new(std::io::sink()));
+        }
+        // The tty is used for what was not given
+        let use_tty = self.input.is_none() || self.output.is_none();
+        let tty = if use_tty {
+            Some(get_tty()?.into_raw_mode()?)
+        } else {
+            None
+        };
+        let size = match self.size {
+            Some(size) => size,
+            None if use_tty => get_terminal_size()?,
+            None => Size {
+                width: 80,
+                height: 24,
+            },
+        };
+        let input: Box<dyn Read + Send> = match (self.input, &tty) {
+            (Some(input), _) => input,
+            (None, Some(tty)) => Box::new(tty.try_clone()?),
+            (None, None) => unreachable!("the tty is open without input"),
+        };
+        let mut output: Box<dyn Write + Send> = match (self.output, tty) {
+            (Some(output), _) => output,
+            // Dropping the tty restores its mode
+            (None, Some(tty)) => Box::new(tty),
+            (None, None) => unreachable!("the tty is open without output"),
+        };
+
+        let shell = self.program.unwrap_or_else(get_shell);
+        let screen = Arc::new(Mutex::new(Screen::new(
+            size.width,
+            size.height,
+            self.scrollback,
+        )));
+        let recording = match self.record {
+            Some(path) => {
+                let (width, height) = screen.lock().unwrap().size();
+                Some(Recorder::create(&path, width, height, &shell)?)
+            }
+            None => None,
+        };
+        let mut cmd = process::Command::new(&shell);
+        let mut state = ShellState::default();
+        let kind =
+            ShellKind::detect(&shell).filter(|_| self.shell_integration && self.args.is_empty());
+        if let Some(kind) = kind {
+            match integration::inject(&mut cmd, kind, &endpoint::runtime_dir()?) {
+                Ok(()) => {
+                    state.first_prompt_deadline = Some(Instant::now() + FIRST_PROMPT_TIMEOUT);
+                    state.marks_input = true;
+                }
+                Err(err) => warn!("Unable to set the shell integration up: {}", err),
+            }
+        }
+        cmd.args(&self.args);
+        for (key, value) in self.env {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+        if let Some(cwd) = self.cwd {
+            cmd.current_dir(cwd);
+        }
+        let pty = match Pty::spawn_command(cmd, &size) {
+            Ok(pty) => Arc::new(pty),
+            Err(err) => bail!("Unable to start {}: {:?}", shell, err),
+        };
+        let clones = pty
+            .try_clone()
+            .and_then(|output| Ok((output, pty.try_clone()?)));
+        let (pty_output, mut pty_input) = match clones {
+            Ok(clones) => clones,
+            Err(err) => {
+                // Nothing follows the shell yet
+                let _ = pty.signal(libc::SIGKILL);
+                let _ = pty.wait();
+                bail!(err);
+            }
+        };
+
+        let (cmd_sender, cmd_receiver) = crossbeam_channel::unbounded();
+        let (val_sender, val_receiver) = crossbeam_channel::unbounded();
+
+        let waiters = Waiters::default();
+        let queue = CommandQueue::start(
+            pty.clone(),
+            cmd_sender.clone(),
+            Arc::new(Mutex::new(state)),
+            waiters.clone(),
+            &shell,
+        );
+        let context = Context {
+            name: self.name.clone(),
+            shell,
+            queue,
+            cmd_sender,
+            pty: pty.clone(),
+            waiters,
+            expectations: Expectations::default(),
+            viewers: Viewers::default(),
+            screen,
+            recording: Arc::new(Mutex::new(recording)),
+            started,
+            allowed_uids: self.allowed_uids,
+            detached: self.detached,
+            tasks: Arc::new(self.tasks),
+        };
+        if let Some(command) = self.startup_command {
+            let cmd = format!("{}\n", command);
+            // Typed once the thread writing to the shell starts
+            let _ = context.cmd_sender.send(Vec::from(cmd));
+        }
+        // The server stops once the shell exited, even when programs it left
+        // in the background still hold the terminal
+        let (exit_sender, exited) = crossbeam_channel::bounded::<()>(0);
+        let shell_pty = pty.clone();
+        let shell_thread = spawn_with_name("WaitShell", move || {
+            let status = shell_pty.wait();
+            drop(exit_sender);
+            status
+        });
+        let stopper = Stopper {
+            name: self.name.clone(),
+            socket,
+            pty,
+            stopped: Arc::default(),
+            exited: exited.clone(),
+        };
+
+        let output_context = context.clone();
+        let output_thread = spawn_with_name("HandleMasterOutput", move || {
+            let context = output_context;
+            let result = handle_master_output(&mut pty_input, &mut output, &context, &exited);
+            // Nothing will finish or be printed anymore, closing the
+            // connections tells the clients
+            context.waiters.lock().unwrap().clear();
+            context.expectations.lock().unwrap().clear();
+            context.viewers.lock().unwrap().clear();
+            result
+        });
+
+        spawn_with_name("ReadCmdTerm", move || read_input(input, val_sender));
+
+        let recording = context.recording.clone();
+        spawn_with_name("HandleSlaveOutput", move || {
+            handle_slave_output(cmd_receiver, val_receiver, pty_output, &recording)
+        });
+
+        if self.handle_signals {
+            let stopper = stopper.clone();
+            let context = context.clone();
+            spawn_with_name("SignalHandler", move || handle_signals(stopper, &context));
+        }
+        //Read commands from the clients and push them to the channel
+        let stopped = stopper.stopped.clone();
+        spawn_with_name("AcceptClients", move || {
+            accept_clients(listener, context, stopped)
+        });
+
+        Ok(Server {
+            stopper,
+            output_thread,
+            shell_thread,
+        })
+    }
 }
 
-pub fn server(name: String, program: Option<&str>) -> Result<()> {
-    let mut tty_output = get_tty().unwrap().into_raw_mode().unwrap();
-    let mut tty_input = tty_output.try_clone().unwrap();
+impl Server {
+    /// Name the server listens under.
+    pub fn name(&self) -> &str {
+        &self.stopper.name
+    }
 
-    let pty_resize = Pty::spawn(&get_shell(), &get_terminal_size().unwrap()).unwrap();
-    let pty_output = pty_resize.try_clone().unwrap();
-    let mut pty_input = pty_output.try_clone().unwrap();
+    /// Asks the program in the terminal to exit and stops accepting clients.
+    ///
+    /// [`Server::wait`] returns once the program exited.
+    pub fn shutdown(&self) {
+        self.stopper.stop();
+    }
 
-    let (cmd_sender, cmd_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
-    let (val_sender, val_receiver): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel();
+    /// Waits for the program in the terminal to exit, then stops the server.
+    ///
+    /// Returns the exit status of the program. The tty mode is restored and
+    /// the socket removed once it returns.
+    pub fn wait(self) -> Result<i32> {
+        // The output thread owns the tty, its mode is restored when it ends
+        let result = match self.output_thread.join() {
+            Ok(result) => result,
+            Err(e) => panic::resume_unwind(e),
+        };
+        if let Err(e) = &result {
+            error!("Output failed: {:?}", e);
+            // The program may still run
+            self.stopper.stop();
+        }
+        self.stopper.stopped.store(true, Ordering::SeqCst);
+        // Wakes the thread accepting clients up so it sees the server stopped
+        if let Err(e) = UnixStream::connect(&self.stopper.socket) {
+            debug!("Unable to wake the accept thread: {:?}", e);
+        }
+        if let Err(e) = std::fs::remove_file(&self.stopper.socket) {
+            error!("Unable to delete socket {:?}", e);
+        }
+        let status = match self.shell_thread.join() {
+            Ok(status) => status?,
+            Err(e) => panic::resume_unwind(e),
+        };
+        debug!("{} exited with {}", self.stopper.name, status);
+        result.map(|()| status)
+    }
+}
 
-    let handle: thread::JoinHandle<Result<(), anyhow::Error>> = thread::spawn(move || loop {
-        if let Err(err) = pipe(&mut pty_input, &mut tty_output) {
-            bail!(err)
+impl Stopper {
+    /// Hangs the shell up, the server stops once it exited. The shell and
+    /// its foreground program are killed if it did not exit after
+    /// [`STOP_TIMEOUT`].
+    fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
         }
-    });
+        debug!("Stopping server {}", self.name);
+        if let Err(e) = self.pty.signal(libc::SIGHUP) {
+            debug!("Unable to hang up the shell: {:?}", e);
+        }
+        let stopper = self.clone();
+        spawn_with_name("KillShell", move || stopper.kill_after(STOP_TIMEOUT));
+    }
 
-    spawn_with_name("ReadCmdTerm", move || loop {
-        let mut packet = [0; 4096];
+    /// Kills the process groups of the shell and of its foreground program
+    /// unless the shell exits before the timeout.
+    fn kill_after(&self, timeout: Duration) {
+        if self.exited.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+        warn!("{} did not exit once hung up, killing it", self.name);
+        // Unknown once the shell is gone, the foreground group is killed first
+        if let Err(e) = self.pty.signal_foreground(libc::SIGKILL) {
+            debug!("Unable to kill the foreground program: {:?}", e);
+        }
+        if let Err(e) = self.pty.signal_group(libc::SIGKILL) {
+            debug!("Unable to kill the shell: {:?}", e);
+        }
+    }
+}
 
-        let count = tty_input.read(&mut packet).unwrap();
-        let (sub_slice, _) = packet.split_at(count);
-        if let Err(err) = val_sender.send(sub_slice.to_vec()) {
-            panic!("Fail to send: {}", err);
+/// Sends the shell output to the output sink, to the screen, to the
+/// recording and to the clients, until the shell exited.
+fn handle_master_output(
+    pty_input: &mut File,
+    output: &mut dyn Write,
+    context: &Context,
+    exited: &Receiver<()>,
+) -> Result<()> {
+    let Context {
+        waiters,
+        queue: CommandQueue { state, .. },
+        screen,
+        expectations,
+        recording,
+        viewers,
+        ..
+    } = context;
+    let mut scanner = SequenceScanner::default();
+    let mut plain = PlainText::default();
+    let mut drained_by = None;
+    loop {
+        // Checked first, what the shell printed before exiting is readable
+        let shell_exited = exited.try_recv() == Err(TryRecvError::Disconnected);
+        let readable = wait_readable(pty_input, OUTPUT_POLL)?;
+        if shell_exited {
+            // Programs left in the background may keep the pty open
+            let drained_by = *drained_by.get_or_insert_with(|| Instant::now() + EXIT_DRAIN);
+            if !readable || Instant::now() > drained_by {
+                return Ok(());
+            }
+        } else if !readable {
+            continue;
         }
-    });
+        let read = match pipe(pty_input, output) {
+            Ok(read) if read.is_empty() => return Ok(()),
+            Ok(read) => read,
+            // The pty is closed once the shell exited
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => return Ok(()),
+            Err(err) => bail!(err),
+        };
+        state.lock().unwrap().modes.update(&read);
+        {
+            // The viewers get what follows the screen they got
+            let mut viewers = viewers.lock().unwrap();
+            screen.lock().unwrap().process(&read);
+            viewers.retain(|id, viewer| match viewer.try_send(read.clone()) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("Client {} stopped following the output: {}", id, err);
+                    false
+                }
+            });
+        }
+        record(recording, |recorder| recorder.output(&read));
+        match_output(expectations, &plain.strip(&read));
+        for event in scanner.scan(&read) {
+            handle_event(event, waiters, state);
+        }
+    }
+}
 
-    spawn_with_name("HandleSlaveOutput", move || {
-        handle_slave_output(cmd_receiver, val_receiver, pty_output)
-    });
+fn handle_event(event: OutputEvent, waiters: &Waiters, state: &SharedState) {
+    match event {
+        OutputEvent::Data(data) => tee_output(waiters, &data),
+        OutputEvent::Status(id, status) => reply_status(waiters, id, status),
+        OutputEvent::Marker(marker) => handle_marker(marker, waiters, state),
+    }
+}
+
+/// Follows the commands of the shell from its markers.
+fn handle_marker(marker: Marker, waiters: &Waiters, state: &SharedState) {
+    let mut state = state.lock().unwrap();
+    match marker {
+        // The command is typed once the shell reads it, after the prompt
+        Marker::PromptStart => {
+            state.integrated = true;
+            state.at_prompt = !state.marks_input;
+            state.prompt_started = Some(Instant::now());
+            state.prompt_missing_since = None;
+        }
+        Marker::CommandInput => {
+            state.integrated = true;
+            state.marks_input = true;
+            state.at_prompt = true;
+            state.prompt_missing_since = None;
+        }
+        Marker::CommandStart => {
+            state.at_prompt = false;
+            state.prompt_started = None;
+            state.prompt_missing_since = Some(Instant::now());
+            if let Some(id) = state.running {
+                if let Some(waiter) = waiters.lock().unwrap().get_mut(&id) {
+                    waiter.echo_skipped = true;
+                }
+            }
+        }
+        Marker::CommandEnd(status) => {
+            state.prompt_missing_since = Some(Instant::now());
+            if let Some(id) = state.running.take() {
+                reply_status(waiters, id, status.unwrap_or_default());
+            }
+        }
+    }
+}
 
-    let name_copy = name.clone();
-    let name_copy2 = name.clone();
-    //Resize thread
-    spawn_with_name("SignalHandler", move || {
-        use signal_hook::consts::signal;
-        let signal = notify(&[signal::SIGWINCH, signal::SIGTERM]).unwrap();
-        let handle_signal = |signal_value| {
-            debug!("Handle signal {}", signal_value);
-            match signal_value {
-                signal::SIGWINCH => {
-                    signal.recv().unwrap();
-                    if let Err(e) = pty_resize.resize(&get_terminal_size().unwrap()) {
+/// Follows the tty size, shuts down on SIGTERM and SIGHUP and forwards SIGINT.
+fn handle_signals(stopper: Stopper, context: &Context) {
+    use signal_hook::consts::signal;
+    let signal = notify(&[
+        signal::SIGWINCH,
+        signal::SIGTERM,
+        signal::SIGHUP,
+        signal::SIGINT,
+    ])
+    .unwrap();
+    let handle_signal = |signal_value| {
+        debug!("Handle signal {}", signal_value);
+        match signal_value {
+            // Only the clients resize the terminal of a detached server
+            signal::SIGWINCH if context.detached => {}
+            signal::SIGWINCH => match get_terminal_size() {
+                Ok(size) => {
+                    if let Err(e) = resize(context, &size) {
                         error!("Resize failed with {:?}", e);
                     }
                 }
-                signal::SIGTERM => {
-                    if let Err(e) = delete_pipe(&name_copy) {
-                        error!("Unable to delete pipe {:?}", e);
-                    }
-                    std::process::exit(0);
+                Err(e) => error!("Unable to get the terminal size: {:?}", e),
+            },
+            signal::SIGTERM | signal::SIGHUP => stopper.stop(),
+            signal::SIGINT => {
+                if let Err(e) = stopper.pty.signal_foreground(signal::SIGINT) {
+                    error!("Unable to interrupt the foreground program: {:?}", e);
                 }
-                _ => {}
+            }
+            _ => {}
+        }
+    };
+    loop {
+        select! {recv(signal) -> signal_value => handle_signal(signal_value.unwrap()),
+        }
+    }
+}
+
+/// Forwards the keys typed in the terminal until the input is closed.
+fn read_input(mut input: Box<dyn Read + Send>, val_sender: crossbeam_channel::Sender<Vec<u8>>) {
+    let mut packet = [0; 4096];
+    loop {
+        let count = match input.read(&mut packet) {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(err) => {
+                error!("Unable to read the input: {}", err);
+                return;
             }
         };
-        loop {
-            select! {recv(signal) -> signal_value => handle_signal(signal_value.unwrap()),
+        let (sub_slice, _) = packet.split_at(count);
+        if val_sender.send(sub_slice.to_vec()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Serves each client on its own thread.
+fn accept_clients(listener: UnixListener, context: Context, stopped: Arc<AtomicBool>) {
+    debug!("accept_clients");
+    for (id, stream) in (1..).zip(listener.incoming()) {
+        if stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        match stream {
+            Ok(stream) => {
+                let context = context.clone();
+                spawn_with_name("HandleClient", move || {
+                    if let Err(err) = authorize(&stream, &context.allowed_uids) {
+                        warn!("Client {} rejected: {}", id, err);
+                        reject(stream, &err.to_string());
+                    } else if let Err(err) = handle_client(stream, id, &context) {
+                        error!("Client {} failed: {}", id, err);
+                    }
+                });
             }
+            Err(err) => error!("Accept failed: {}", err),
         }
-    });
-    if let Some(program) = program {
-        let cmd = format!("{}\n", program);
-        cmd_sender.send(Vec::from(cmd))?;
     }
-    //Read commands from pipe and push it to the channel
-    spawn_with_name("ReadCmdsRemote", move || {
-        read_comands_from_pipe(cmd_sender, &name)
-    });
+}
 
-    if let Err(e) = handle.join() {
-        panic::resume_unwind(e)
+/// Tells the client it is not allowed, in answer to its hello: sending it
+/// fails once the connection is closed.
+fn reject(mut stream: UnixStream, reason: &str) {
+    let reply = Reply::error(ErrorKind::PermissionDenied, reason);
+    let told = read_frame::<_, Request>(&mut stream)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| Ok(write_frame(&mut stream, &reply)?));
+    if let Err(err) = told {
+        debug!("Unable to tell the client it was rejected: {}", err);
     }
+}
 
-    if let Err(e) = delete_pipe(&name_copy2) {
-        error!("Unable to delete pipe {:?}", e);
+/// Checks the client runs as the server user or as an allowed user.
+fn authorize(stream: &UnixStream, allowed_uids: &[u32]) -> Result<()> {
+    let uid = endpoint::peer_uid(stream)?;
+    if !is_allowed(uid, nix::unistd::Uid::current().as_raw(), allowed_uids) {
+        bail!("User {} is not allowed", uid);
     }
     Ok(())
 }
 
-fn read_comands_from_pipe(cmd_sender: Sender<Vec<u8>>, name: &str) -> Result<()> {
-    debug!("read_comands");
-    let mut input = get_pipe(name, false)?;
-    let mut data = [0; 256];
-    loop {
-        match input.read(&mut data) {
-            Ok(count) => {
-                if count == 0 {
-                    let ten_millis = Duration::from_millis(1);
-                    thread::sleep(ten_millis);
-                }
-                debug!("count {}", count);
-                let (sub_slice, _) = data.split_at(count);
-                if let Err(err) = cmd_sender.send(Vec::from(sub_slice)) {
-                    error!("{}", err);
+fn is_allowed(uid: u32, server_uid: u32, allowed_uids: &[u32]) -> bool {
+    uid == server_uid || allowed_uids.contains(&uid)
+}
+
+/// Answers the request of a client.
+fn handle_client(mut stream: UnixStream, id: u64, context: &Context) -> Result<()> {
+    server_handshake(&mut stream)?;
+    let request = match read_frame(&mut stream)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    debug!("Client {} sent {:?}", id, request);
+    let request = match resolve_task(request, context) {
+        Ok(request) => request,
+        Err(reply) => {
+            write_frame(&mut stream, &reply)?;
+            return Ok(());
+        }
+    };
+    let reply = match request {
+        Request::RunCommand(run) => match render_command(
+            &run,
+            &context.shell,
+            process_cwd(context.pty.pid()).as_deref(),
+        )
+        .and_then(|cmd| {
+            if run.paste && find(&cmd, PASTE_END).is_some() {
+                return Err(Reply::error(
+                    ErrorKind::InvalidRequest,
+                    "The command ends the bracketed paste",
+                ));
+            }
+            make_room(context, run.policy, run.timeouts)?;
+            Ok(cmd)
+        }) {
+            Ok(cmd) if run.wait || run.tee => {
+                // The output and the exit status are sent by the output thread
+                let waiter = Waiter::new(id, stream, run.tee);
+                context.waiters.lock().unwrap().insert(id, waiter);
+                context.queue.push(QueuedCommand {
+                    cmd,
+                    waiter: Some(id),
+                    paste: run.paste,
+                })?;
+                return Ok(());
+            }
+            Ok(cmd) => {
+                context.queue.push(QueuedCommand {
+                    cmd,
+                    waiter: None,
+                    paste: run.paste,
+                })?;
+                Reply::Accepted
+            }
+            Err(reply) => reply,
+        },
+        Request::SendKeys { data } => {
+            context.cmd_sender.send(data)?;
+            Reply::Accepted
+        }
+        Request::SendNamedKeys { keys } => {
+            let data = keys::encode_keys(&keys, &context.queue.state.lock().unwrap().modes);
+            context.cmd_sender.send(data)?;
+            Reply::Accepted
+        }
+        Request::Interrupt => {
+            // Ctrl-C
+            context.cmd_sender.send(vec![0x03])?;
+            Reply::Accepted
+        }
+        Request::Resize { width, height } => match resize(context, &Size { width, height }) {
+            Ok(()) => Reply::Accepted,
+            Err(err) => Reply::error(ErrorKind::Internal, format!("{:?}", err)),
+        },
+        Request::Expect {
+            pattern,
+            timeout_ms,
+            recent,
+        } => expect_output(context, id, &pattern, timeout_ms, recent),
+        Request::Attach { read_only } => return attach(stream, id, read_only, context),
+        Request::StartRecording { path } => set_recording(context, Some(path)),
+        Request::StopRecording => set_recording(context, None),
+        Request::Capture { history } => {
+            Reply::Capture(context.screen.lock().unwrap().capture(history))
+        }
+        Request::Query => Reply::Info(Box::new(server_info(context))),
+        Request::Hello { .. } => Reply::error(ErrorKind::InvalidRequest, "Already said hello"),
+        Request::RunTask { .. } => unreachable!("the tasks are resolved first"),
+    };
+    write_frame(&mut stream, &reply)?;
+    Ok(())
+}
+
+/// Turns a task into its command.
+fn resolve_task(request: Request, context: &Context) -> Result<Request, Reply> {
+    match request {
+        Request::RunTask { task, wait, tee } => match context.tasks.get(&task) {
+            Some(command) => Ok(Request::RunCommand(RunCommand {
+                wait,
+                tee,
+                ..command.clone()
+            })),
+            None => Err(Reply::error(
+                ErrorKind::NotFound,
+                format!("No task named {}", task),
+            )),
+        },
+        request => Ok(request),
+    }
+}
+
+/// Sends the screen then the output of the terminal to the client until it
+/// detaches, typing the keys it sends unless it is read only.
+fn attach(stream: UnixStream, id: u64, read_only: bool, context: &Context) -> Result<()> {
+    let (sender, receiver) = crossbeam_channel::bounded(VIEWER_BACKLOG);
+    {
+        let mut viewers = context.viewers.lock().unwrap();
+        let mut repaint = context.screen.lock().unwrap().capture(0).repaint();
+        let modes = &context.queue.state.lock().unwrap().modes;
+        if modes.application_cursor {
+            repaint.push_str("\x1b[?1h");
+        }
+        if modes.bracketed_paste {
+            repaint.push_str("\x1b[?2004h");
+        }
+        sender.send(repaint.into_bytes())?;
+        viewers.insert(id, sender);
+    }
+    let mut output = stream.try_clone()?;
+    spawn_with_name("AttachedOutput", move || {
+        for data in receiver {
+            if let Err(err) = write_frame(&mut output, &Reply::Output { data }) {
+                debug!("Client {} detached: {}", id, err);
+                break;
+            }
+        }
+        // Also ends the client when the shell exited or it was left behind
+        let _ = output.shutdown(std::net::Shutdown::Both);
+    });
+
+    let mut stream = stream;
+    let result = loop {
+        match read_frame(&mut stream) {
+            Ok(Some(Request::SendKeys { data })) if !read_only => {
+                if let Err(err) = context.cmd_sender.send(data) {
+                    break Err(err.into());
                 }
-                data.fill(0);
             }
-            Err(err) => error!("error {}", err),
+            Ok(Some(request)) => warn!("Client {} attached read only sent {:?}", id, request),
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err.into()),
+        }
+    };
+    // Closes the connection once the output sent is written
+    context.viewers.lock().unwrap().remove(&id);
+    result
+}
+
+/// Waits for the shell to print text matching the pattern, or for the last
+/// `recent` lines printed to match it. `^` and `$` match at the line ends.
+fn expect_output(
+    context: &Context,
+    id: u64,
+    pattern: &str,
+    timeout_ms: Option<u64>,
+    recent: usize,
+) -> Reply {
+    let pattern = match RegexBuilder::new(pattern).multi_line(true).build() {
+        Ok(pattern) => pattern,
+        Err(err) => return Reply::error(ErrorKind::InvalidRequest, err.to_string()),
+    };
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let expectation = Expectation {
+        pattern: pattern.clone(),
+        text: String::new(),
+        sender,
+    };
+    // Registered before looking at the screen, to miss nothing printed in between
+    context.expectations.lock().unwrap().insert(id, expectation);
+    if recent > 0 {
+        let text = context.screen.lock().unwrap().capture(recent).to_text();
+        let lines: Vec<&str> = text.lines().collect();
+        let recent_text = lines[lines.len().saturating_sub(recent)..].join("\n");
+        if let Some(found) = pattern.find(&recent_text) {
+            context.expectations.lock().unwrap().remove(&id);
+            return Reply::Matched {
+                text: found.as_str().to_string(),
+            };
         }
     }
+    let received = match timeout_ms {
+        Some(timeout_ms) => receiver
+            .recv_timeout(Duration::from_millis(timeout_ms))
+            .map_err(|err| err.is_timeout()),
+        None => receiver.recv().map_err(|_| false),
+    };
+    context.expectations.lock().unwrap().remove(&id);
+    match received {
+        Ok(text) => Reply::Matched { text },
+        Err(true) => Reply::error(ErrorKind::Timeout, format!("{} was not printed", pattern)),
+        Err(false) => Reply::error(ErrorKind::Internal, "The shell exited"),
+    }
 }
 
-//Pass all cmds to the terminal
-fn handle_slave_output(
-    cmd_receiver: Receiver<Vec<u8>>,
-    val_receiver: Receiver<Vec<u8>>,
-    mut pty_output: File,
-) -> std::io::Result<()> {
-    loop {
-        if let Ok(val) = cmd_receiver.try_recv() {
-            pty_output.write_all(val.as_slice())?;
-            pty_output.flush()?;
+/// Adds the output to the text of the expectations, the matched ones are
+/// answered and removed.
+fn match_output(expectations: &Expectations, output: &str) {
+    if output.is_empty() {
+        return;
+    }
+    expectations.lock().unwrap().retain(|_, expectation| {
+        let text = &mut expectation.text;
+        text.push_str(output);
+        if text.len() > MAX_EXPECTED_TEXT {
+            let mut start = text.len() - MAX_EXPECTED_TEXT;
+            while !text.is_char_boundary(start) {
+                start += 1;
+            }
+            text.drain(..start);
+        }
+        match expectation.pattern.find(text) {
+            Some(found) => {
+                // The client may have timed out already
+                let _ = expectation.sender.try_send(found.as_str().to_string());
+                false
+            }
+            None => true,
         }
+    });
+}
+
+/// Resizes the terminal and its screen.
+fn resize(context: &Context, size: &Size) -> Result<(), PtyError> {
+    context.pty.resize(size)?;
+    context
+        .screen
+        .lock()
+        .unwrap()
+        .resize(size.width, size.height);
+    record(&context.recording, |recorder| {
+        recorder.resize(size.width, size.height)
+    });
+    Ok(())
+}
 
-        if let Ok(val) = val_receiver.try_recv() {
-            pty_output.write_all(val.as_slice())?;
-            pty_output.flush()?;
+/// Starts recording the terminal in the file, or stops the recording.
+fn set_recording(context: &Context, path: Option<PathBuf>) -> Reply {
+    let mut recording = context.recording.lock().unwrap();
+    let path = match (path, recording.as_ref()) {
+        (Some(_), Some(recorder)) => {
+            return Reply::error(
+                ErrorKind::InvalidRequest,
+                format!("Already recording to {}", recorder.path().display()),
+            )
+        }
+        (Some(path), None) => path,
+        (None, Some(_)) => {
+            *recording = None;
+            return Reply::Accepted;
         }
+        (None, None) => return Reply::error(ErrorKind::InvalidRequest, "Not recording"),
+    };
+    let (width, height) = context.screen.lock().unwrap().size();
+    match Recorder::create(&path, width, height, &context.shell) {
+        Ok(recorder) => {
+            *recording = Some(recorder);
+            Reply::Accepted
+        }
+        Err(err) => Reply::error(
+            ErrorKind::Internal,
+            format!("Unable to record to {}: {}", path.display(), err),
+        ),
     }
 }
 
-/// Sends the content of input into output
-fn pipe(input: &mut File, output: &mut File) -> Result<()> {
-    let mut packet = [0; 4096];
+/// Writes an event to the recording, if any. The recording stops on errors.
+fn record(recording: &Recording, event: impl FnOnce(&mut Recorder) -> std::io::Result<()>) {
+    let mut recording = recording.lock().unwrap();
+    if let Some(recorder) = recording.as_mut() {
+        if let Err(err) = event(recorder) {
+            error!(
+                "Recording to {} stopped: {}",
+                recorder.path().display(),
+                err
+            );
+            *recording = None;
+        }
+    }
+}
 
-    let count = input.read(&mut packet)?;
+/// Returns the current directory of the process.
+fn process_cwd(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()
+}
 
-    let read = &packet[..count];
-    output.write_all(read)?;
-    output.flush()?;
+fn server_info(context: &Context) -> ServerInfo {
+    let foreground = context.pty.foreground_process_group().ok();
+    ServerInfo {
+        name: context.name.clone(),
+        version: PROTOCOL_VERSION,
+        pid: process::id(),
+        shell: context.shell.clone(),
+        shell_pid: context.pty.pid(),
+        cwd: foreground.and_then(process_cwd),
+        started: context.started,
+        busy: context.queue.is_busy(&context.pty),
+        detached: context.detached,
+        queued: context.queue.queued(),
+        waiting_ms: context
+            .queue
+            .waiting()
+            .map(|waiting| waiting.as_millis() as u64),
+        tasks: {
+            let mut tasks: Vec<String> = context.tasks.keys().cloned().collect();
+            tasks.sort();
+            tasks
+        },
+    }
+}
+
+/// Applies the policy of a command when the shell is busy.
+fn make_room(
+    context: &Context,
+    policy: BusyPolicy,
+    timeouts: InterruptTimeouts,
+) -> Result<(), Reply> {
+    match policy {
+        BusyPolicy::Queue => Ok(()),
+        BusyPolicy::Reject if context.queue.is_busy(&context.pty) => Err(Reply::error(
+            ErrorKind::Busy,
+            "A program is running in the shell",
+        )),
+        BusyPolicy::Reject => Ok(()),
+        BusyPolicy::Interrupt => interrupt_program(&context.pty, timeouts)
+            .map_err(|err| Reply::error(ErrorKind::Internal, err.to_string())),
+    }
+}
 
+/// Stops the program in the foreground of the terminal, if any.
+///
+/// Sends SIGINT, then SIGTERM and SIGKILL while the program is still running
+/// after the timeouts.
+fn interrupt_program(pty: &Pty, timeouts: InterruptTimeouts) -> std::io::Result<()> {
+    let steps = [
+        (libc::SIGINT, timeouts.term_after_ms),
+        (libc::SIGTERM, timeouts.kill_after_ms),
+        (libc::SIGKILL, 0),
+    ];
+    for (signal, timeout) in steps {
+        if !is_running_program(pty) {
+            return Ok(());
+        }
+        debug!("Sending signal {} to the foreground program", signal);
+        pty.signal_foreground(signal)?;
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+        while is_running_program(pty) && Instant::now() < deadline {
+            thread::sleep(IDLE_POLL);
+        }
+    }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn read_write_pipe() {
-        use flexi_logger::*;
-        Logger::try_with_str("info")
-            .unwrap()
-            .log_to_file(FileSpec::default()) // write logs to file
-            .duplicate_to_stderr(Duplicate::Info) // print warnings and errors also to the console
-            .format(with_thread)
-            .start()
-            .unwrap();
+/// Tells if a program started by the shell is in the foreground of the terminal.
+fn is_running_program(pty: &Pty) -> bool {
+    pty.foreground_process_group()
+        .is_ok_and(|pgrp| pgrp != pty.pid())
+}
+
+impl ShellState {
+    /// Tells if the shell waits for a command.
+    ///
+    /// A shell no longer printing its prompts after a command is followed
+    /// from its foreground process, like the shells without markers.
+    fn is_idle(&mut self, pty: &Pty) -> bool {
+        let prompt_missing = self
+            .prompt_missing_since
+            .is_some_and(|since| since.elapsed() > MISSING_PROMPT_TIMEOUT);
+        if self.integrated && prompt_missing && !is_running_program(pty) {
+            warn!("The shell stopped printing its prompt markers, a prompt hook may be broken");
+            // The status of the command typed last will not come either
+            self.integrated = false;
+            self.at_prompt = false;
+            self.prompt_started = None;
+            self.prompt_missing_since = None;
+            self.running = None;
+        }
+        if self.integrated {
+            // Without the end of the prompt, the shell is idle when it is in
+            // the foreground once the prompt had the time to print
+            let prompt_printed = self
+                .prompt_started
+                .is_some_and(|started| started.elapsed() > MISSING_INPUT_MARK_DELAY);
+            self.at_prompt || (prompt_printed && !is_running_program(pty))
+        } else if self
+            .first_prompt_deadline
+            .is_some_and(|deadline| Instant::now() < deadline)
+        {
+            false
+        } else {
+            !is_running_program(pty)
+        }
+    }
+}
+
+impl CommandQueue {
+    /// Starts the thread typing the queued commands once the shell is idle.
+    ///
+    /// Without the markers, the waited commands are wrapped to report their
+    /// status for the given shell.
+    fn start(
+        pty: Arc<Pty>,
+        cmd_sender: Sender<Vec<u8>>,
+        state: SharedState,
+        waiters: Waiters,
+        shell: &str,
+    ) -> CommandQueue {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let queue = CommandQueue {
+            sender,
+            progress: Arc::default(),
+            state,
+        };
+        let progress = queue.progress.clone();
+        let state = queue.state.clone();
+        let shell = shell.to_string();
+        spawn_with_name("CommandQueue", move || {
+            run_queued_commands(
+                receiver, &progress, &pty, &state, &waiters, &shell, cmd_sender,
+            )
+        });
+        queue
+    }
+
+    /// Queues the command.
+    fn push(&self, command: QueuedCommand) -> Result<()> {
+        self.progress.pending.fetch_add(1, Ordering::SeqCst);
+        self.sender.send(command)?;
+        Ok(())
+    }
+
+    /// Tells if a program runs in the shell or commands are waiting for it.
+    fn is_busy(&self, pty: &Pty) -> bool {
+        self.queued() > 0 || !self.state.lock().unwrap().is_idle(pty)
+    }
+
+    /// Number of commands not typed yet.
+    fn queued(&self) -> usize {
+        self.progress.pending.load(Ordering::SeqCst)
+    }
+
+    /// How long the next command has been waiting for the shell to be idle.
+    fn waiting(&self) -> Option<Duration> {
+        let waiting_since = *self.progress.waiting_since.lock().unwrap();
+        waiting_since.map(|since| since.elapsed())
+    }
+}
+
+/// Types the queued commands in order, each one once the shell is idle.
+fn run_queued_commands(
+    receiver: Receiver<QueuedCommand>,
+    progress: &QueueProgress,
+    pty: &Pty,
+    state: &SharedState,
+    waiters: &Waiters,
+    shell: &str,
+    cmd_sender: Sender<Vec<u8>>,
+) {
+    for QueuedCommand {
+        mut cmd,
+        waiter,
+        paste,
+    } in receiver
+    {
+        let waiting_since = Instant::now();
+        *progress.waiting_since.lock().unwrap() = Some(waiting_since);
+        let mut warned = false;
+        while !state.lock().unwrap().is_idle(pty) {
+            if !warned && waiting_since.elapsed() > STALLED_QUEUE_WARNING {
+                warn!(
+                    "A command waits for the shell to be idle since {:?}, with {} queued",
+                    waiting_since.elapsed(),
+                    progress.pending.load(Ordering::SeqCst)
+                );
+                warned = true;
+            }
+            thread::sleep(IDLE_POLL);
+        }
+        *progress.waiting_since.lock().unwrap() = None;
+        let (integrated, bracketed_paste) = {
+            let mut state = state.lock().unwrap();
+            if state.integrated {
+                // The markers tell when the command starts and finishes
+                state.at_prompt = false;
+                state.prompt_started = None;
+                state.running = waiter;
+            }
+            (state.integrated, state.modes.bracketed_paste)
+        };
+        if let Some(id) = waiter.filter(|_| !integrated) {
+            if let Some(waiter) = waiters.lock().unwrap().get_mut(&id) {
+                waiter.wrapped = true;
+            }
+            cmd = wrap_with_status(&cmd, id, shell);
+        }
+        if paste && bracketed_paste {
+            cmd = bracket_paste(&cmd);
+        }
+        if cmd_sender.send(cmd).is_err() {
+            return;
+        }
+        if !integrated {
+            // The shell may not have started the command yet
+            thread::sleep(COMMAND_SETTLE);
+        }
+        progress.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Helper running a command in another directory in fish, which has no
+/// subshell: `__parterm_cwd <dir> <command line>`.
+const FISH_CWD_FUNCTION: &str =
+    "function __parterm_cwd; set -l previous $PWD; cd $argv[1]; or return; \
+     eval $argv[2]; set -l result $status; cd $previous; return $result; end";
+
+/// Turns the command into the text typed in the given shell.
+///
+/// The arguments are quoted unless the command is raw. The directory is
+/// changed in a subshell, so the shell stays where it is, unless `cd` is set.
+/// Nothing is done for the directory when the shell is already there.
+fn render_command(
+    run: &RunCommand,
+    shell: &str,
+    shell_cwd: Option<&Path>,
+) -> Result<Vec<u8>, Reply> {
+    let invalid = |err: anyhow::Error| Reply::error(ErrorKind::InvalidRequest, err.to_string());
+    if run.argv.is_empty() {
+        return Err(Reply::error(ErrorKind::InvalidRequest, "Empty command"));
+    }
+    let dialect = Dialect::detect(shell);
+    let quoting = || {
+        dialect.ok_or_else(|| {
+            Reply::error(
+                ErrorKind::Unsupported,
+                format!("Unable to quote the command for {}", shell),
+            )
+        })
+    };
+    let command = if run.raw {
+        run.argv.join(" ")
+    } else {
+        quote::join(&run.argv, quoting()?).map_err(invalid)?
+    };
+    let cwd = run.cwd.as_deref().filter(|&cwd| Some(cwd) != shell_cwd);
+    if cwd.is_none() && run.env.is_empty() {
+        return Ok(format!("{}\n", command).into_bytes());
+    }
+
+    let dialect = quoting()?;
+    let mut assignments = Vec::new();
+    for (name, value) in &run.env {
+        quote::check_variable_name(name).map_err(invalid)?;
+        let value = quote::quote(value, dialect).map_err(invalid)?;
+        assignments.push((name.as_str(), value));
+    }
+    let assigned = assignments
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(" ");
+    // The command with its environment, in the current directory
+    let line = match (dialect, run.raw) {
+        _ if assignments.is_empty() => command.clone(),
+        (Dialect::Fish, true) => {
+            let set: Vec<String> = assignments
+                .iter()
+                .map(|(name, value)| format!("set -lx {} {}; ", name, value))
+                .collect();
+            format!("begin; {}{}; end", set.concat(), command)
+        }
+        (_, true) => format!("(export {}; {})", assigned, command),
+        (_, false) => format!("{} {}", assigned, command),
+    };
+    let cwd = match cwd {
+        Some(cwd) => cwd
+            .to_str()
+            .ok_or_else(|| invalid(anyhow::anyhow!("{:?} is not valid UTF-8", cwd)))?,
+        None => return Ok(format!("{}\n", line).into_bytes()),
+    };
+    let quoted_cwd = quote::quote(cwd, dialect).map_err(invalid)?;
+    let line = match dialect {
+        _ if run.cd => format!("cd {} && {}", quoted_cwd, line),
+        Dialect::Fish => format!(
+            "{}; __parterm_cwd {} {}",
+            FISH_CWD_FUNCTION,
+            quoted_cwd,
+            quote::quote(&line, dialect).map_err(invalid)?
+        ),
+        _ if run.raw && !assignments.is_empty() => {
+            format!(
+                "(cd {} || exit; export {}; {})",
+                quoted_cwd, assigned, command
+            )
+        }
+        _ => format!("(cd {} || exit; {})", quoted_cwd, line),
+    };
+    Ok(format!("{}\n", line).into_bytes())
+}
+
+/// Appends to the command a printf reporting its exit status with the given id.
+fn wrap_with_status(cmd: &[u8], id: u64, shell: &str) -> Vec<u8> {
+    let status_var = if shell.ends_with("fish") {
+        "$status"
+    } else {
+        "$?"
+    };
+    let cmd = cmd.strip_suffix(b"\n").unwrap_or(cmd);
+    let mut wrapped = cmd.to_vec();
+    wrapped.extend_from_slice(
+        format!("; printf '\\033]6973;%s;%s\\007' {} {}\n", id, status_var).as_bytes(),
+    );
+    wrapped
+}
+
+/// Puts the command line between the bracketed paste sequences, the shell
+/// reads all its lines before the final newline runs them.
+fn bracket_paste(cmd: &[u8]) -> Vec<u8> {
+    let cmd = cmd.strip_suffix(b"\n").unwrap_or(cmd);
+    let mut pasted = PASTE_START.to_vec();
+    pasted.extend_from_slice(cmd);
+    pasted.extend_from_slice(PASTE_END);
+    pasted.push(b'\n');
+    pasted
+}
+
+/// Writes the exit status to the client waiting for the command with the given id.
+fn reply_status(waiters: &Waiters, id: u64, status: i32) {
+    let waiter = match waiters.lock().unwrap().remove(&id) {
+        Some(waiter) => waiter,
+        None => return,
+    };
+    debug!("Command {} exited with {}", id, status);
+    if let Err(err) = waiter.sender.try_send(Reply::Exited { status }) {
+        error!("Client {} did not get the exit status: {}", id, err);
+    }
+}
+
+/// Sends the shell output to the clients that asked for the output of their command.
+fn tee_output(waiters: &Waiters, data: &[u8]) {
+    waiters.lock().unwrap().retain(|id, waiter| {
+        if !waiter.tee {
+            return true;
+        }
+        let data = if waiter.echo_skipped {
+            data.to_vec()
+        } else {
+            if !waiter.wrapped {
+                // The output starts with the command start marker
+                return true;
+            }
+            // The echo of the command ends with the arguments of the status
+            // printf, maybe split between reads
+            let echo = format!("007' {} ", id);
+            let mut tail = std::mem::take(&mut waiter.echo_tail);
+            tail.extend_from_slice(data);
+            let begin = find(&tail, echo.as_bytes());
+            let end = begin.and_then(|begin| {
+                tail[begin..]
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map(|end| begin + end)
+            });
+            match (begin, end) {
+                (_, Some(end)) => {
+                    waiter.echo_skipped = true;
+                    tail.split_off(end + 1)
+                }
+                (Some(begin), None) => {
+                    waiter.echo_tail = tail.split_off(begin);
+                    return true;
+                }
+                (None, None) => {
+                    let keep = tail.len().saturating_sub(echo.len() - 1);
+                    waiter.echo_tail = tail.split_off(keep);
+                    return true;
+                }
+            }
+        };
+        if data.is_empty() {
+            return true;
+        }
+        let output = Reply::Output { data };
+        // A client not reading its output must not block the terminal
+        match waiter.sender.try_send(output) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Client {} stopped following the output: {}", id, err);
+                false
+            }
+        }
+    });
+}
+
+/// What the shell output is made of.
+#[derive(Debug, PartialEq, Eq)]
+enum OutputEvent {
+    /// Output without the status and marker sequences
+    Data(Vec<u8>),
+    /// The command with the given id exited with the given status
+    Status(u64, i32),
+    /// The shell printed a marker
+    Marker(Marker),
+}
+
+/// Where a sequence ends.
+enum SequenceEnd {
+    /// The body is followed by a terminator of the given length
+    Terminated(usize, usize),
+    /// The terminator may come with the next read
+    Incomplete,
+    /// Not a sequence
+    Invalid,
+}
+
+/// Finds the exit status and marker sequences in the shell output.
+///
+/// Keeps the start of a sequence split between two reads until the rest arrives.
+#[derive(Default)]
+struct SequenceScanner {
+    pending: Vec<u8>,
+}
+
+impl SequenceScanner {
+    /// Splits the data into plain output, exit statuses and markers, in order.
+    fn scan(&mut self, data: &[u8]) -> Vec<OutputEvent> {
+        self.pending.extend_from_slice(data);
+        let mut events = Vec::new();
+        let mut start = 0;
+        loop {
+            let rest = &self.pending[start..];
+            let begin = match find(rest, OSC) {
+                Some(begin) => begin,
+                None => {
+                    // Keep what could be the start of a sequence
+                    let keep = usize::from(rest.ends_with(&OSC[..1]));
+                    push_data(&mut events, &rest[..rest.len() - keep]);
+                    start += rest.len() - keep;
+                    break;
+                }
+            };
+            let sequence = &rest[begin..];
+            let prefix = match [STATUS_OSC, MARKER_OSC]
+                .into_iter()
+                .find(|prefix| sequence.starts_with(prefix))
+            {
+                Some(prefix) => prefix,
+                None if STATUS_OSC.starts_with(sequence) || MARKER_OSC.starts_with(sequence) => {
+                    push_data(&mut events, &rest[..begin]);
+                    start += begin;
+                    break;
+                }
+                None => {
+                    // Another sequence, left in the output
+                    push_data(&mut events, &rest[..begin + 1]);
+                    start += begin + 1;
+                    continue;
+                }
+            };
+            let body = &sequence[prefix.len()..];
+            match sequence_end(body) {
+                SequenceEnd::Terminated(end, len) => {
+                    push_data(&mut events, &rest[..begin]);
+                    let event = if prefix == STATUS_OSC {
+                        parse_status(&body[..end])
+                            .map(|(id, status)| OutputEvent::Status(id, status))
+                    } else {
+                        integration::parse_marker(&body[..end]).map(OutputEvent::Marker)
+                    };
+                    events.extend(event);
+                    start += begin + prefix.len() + end + len;
+                }
+                SequenceEnd::Incomplete => {
+                    push_data(&mut events, &rest[..begin]);
+                    start += begin;
+                    break;
+                }
+                SequenceEnd::Invalid => {
+                    push_data(&mut events, &rest[..begin + 1]);
+                    start += begin + 1;
+                }
+            }
+        }
+        self.pending.drain(..start);
+        events
+    }
+}
+
+/// Finds the BEL or ST terminating the body of a sequence.
+fn sequence_end(body: &[u8]) -> SequenceEnd {
+    for (index, &byte) in body.iter().enumerate().take(MAX_SEQUENCE_LEN) {
+        match (byte, body.get(index + 1)) {
+            (0x07, _) => return SequenceEnd::Terminated(index, 1),
+            (0x1b, Some(b'\\')) => return SequenceEnd::Terminated(index, 2),
+            (0x1b, Some(_)) => return SequenceEnd::Invalid,
+            (0x1b, None) => return SequenceEnd::Incomplete,
+            _ => {}
+        }
+    }
+    if body.len() < MAX_SEQUENCE_LEN {
+        SequenceEnd::Incomplete
+    } else {
+        SequenceEnd::Invalid
+    }
+}
+
+fn push_data(events: &mut Vec<OutputEvent>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    match events.last_mut() {
+        Some(OutputEvent::Data(last)) => last.extend_from_slice(data),
+        _ => events.push(OutputEvent::Data(data.to_vec())),
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_status(body: &[u8]) -> Option<(u64, i32)> {
+    let body = std::str::from_utf8(body).ok()?;
+    let (id, status) = body.split_once(';')?;
+    Some((id.parse().ok()?, status.parse().ok()?))
+}
 
-        let t = spawn_with_name("Reader", move || {
-            let mut output = get_pipe("test", false).unwrap();
-            let mut data = [0; 256];
-            match output.read(&mut data) {
-                Ok(count) => {
-                    assert_eq!(count, 5);
-                    let mut str = String::from_utf8(data.into()).unwrap();
-                    str.truncate(count);
-                    assert_eq!(str, "12345");
+//Pass all cmds to the terminal
+fn handle_slave_output(
+    cmd_receiver: Receiver<Vec<u8>>,
+    mut val_receiver: Receiver<Vec<u8>>,
+    mut pty_output: File,
+    recording: &Recording,
+) -> std::io::Result<()> {
+    loop {
+        let val = select! {
+            // The commands stop once the server stopped
+            recv(cmd_receiver) -> val => match val {
+                Ok(val) => val,
+                Err(_) => return Ok(()),
+            },
+            recv(val_receiver) -> val => match val {
+                Ok(val) => val,
+                Err(_) => {
+                    // The input was closed, only commands are left
+                    val_receiver = crossbeam_channel::never();
+                    continue;
                 }
-                Err(err) => error!("{}", err),
+            },
+        };
+        pty_output.write_all(val.as_slice())?;
+        pty_output.flush()?;
+        record(recording, |recorder| recorder.input(&val));
+    }
+}
+
+/// Tells if the file has something to read before the timeout.
+fn wait_readable(file: &File, timeout: Duration) -> Result<bool> {
+    let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::POLLIN)];
+    match poll(&mut fds, timeout.as_millis() as c_int) {
+        Ok(ready) => Ok(ready > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(err) => bail!(err),
+    }
+}
+
+/// Sends the content of input into output
+/// Returns the data that was sent.
+fn pipe(input: &mut File, output: &mut dyn Write) -> std::io::Result<Vec<u8>> {
+    let mut packet = [0; 4096];
+
+    let count = input.read(&mut packet)?;
+
+    let read = &packet[..count];
+    output.write_all(read)?;
+    output.flush()?;
+
+    Ok(read.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Attachment, Client};
+
+    /// Server running sh without terminal, to chain more settings to.
+    fn test_server(name: &str) -> ServerBuilder {
+        ServerBuilder::new(name)
+            .program("/bin/sh")
+            .input(std::io::empty())
+            .output(std::io::sink())
+    }
+
+    /// Waits a few seconds for the condition, returns if it became true.
+    fn wait_until(cond: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if cond() {
+                return true;
             }
+            thread::sleep(IDLE_POLL);
+        }
+        cond()
+    }
+
+    #[test]
+    fn wait_request_is_answered_on_the_same_connection() {
+        let name = format!("test_{}", std::process::id());
+        let listener = endpoint::bind(&name).unwrap();
+        let (cmd_sender, cmd_receiver) = crossbeam_channel::unbounded();
+        let size = Size {
+            width: 100,
+            height: 100,
+        };
+        let pty = Arc::new(Pty::spawn("/bin/sh", &size).unwrap());
+        let waiters = Waiters::default();
+        let context = Context {
+            name: name.clone(),
+            shell: "/bin/sh".to_string(),
+            queue: CommandQueue::start(
+                pty.clone(),
+                cmd_sender.clone(),
+                SharedState::default(),
+                waiters.clone(),
+                "/bin/sh",
+            ),
+            cmd_sender,
+            pty,
+            waiters: waiters.clone(),
+            expectations: Expectations::default(),
+            viewers: Viewers::default(),
+            screen: Arc::new(Mutex::new(Screen::new(100, 100, 0))),
+            recording: Recording::default(),
+            started: 0,
+            allowed_uids: vec![],
+            detached: false,
+            tasks: Arc::default(),
+        };
+        spawn_with_name("AcceptClients", move || {
+            accept_clients(listener, context, Arc::default())
         });
-        let ten_millis = Duration::from_millis(10);
-        thread::sleep(ten_millis);
-        let mut pipe = get_pipe("test", true).unwrap();
-        pipe.write("12345".as_bytes()).unwrap();
-        t.join().unwrap();
+
+        let client = Client::connect(&name).unwrap();
+        let mut handle = client.run(["make"]).unwrap();
+        // The connection used by connect is client 1
+        let cmd = cmd_receiver.recv().unwrap();
+        assert_eq!(cmd, wrap_with_status(b"make\n", 2, "/bin/sh"));
+        tee_output(&waiters, b"$ previous output\r\n$ ");
+        tee_output(
+            &waiters,
+            b"make; printf '\\033]6973;%s;%s\\007' 2 $?\r\nBuilding\r\n",
+        );
+        reply_status(&waiters, 2, 2);
+        let output: Vec<Vec<u8>> = handle.output_stream().map(Result::unwrap).collect();
+        assert_eq!(output, vec![b"Building\r\n".to_vec()]);
+        assert_eq!(handle.wait().unwrap(), 2);
+
+        let ls = RunCommand {
+            argv: vec!["ls".to_string()],
+            ..Default::default()
+        };
+        client.execute(ls).unwrap();
+        assert_eq!(cmd_receiver.recv().unwrap(), b"ls\n");
+
+        client.resize(80, 24).unwrap();
+
+        // The queue is busy while the shell starts the last command
+        thread::sleep(COMMAND_SETTLE * 2);
+        let info = client.status().unwrap();
+        assert_eq!(info.name, name);
+        assert_eq!(info.pid, process::id());
+        assert!(!info.busy);
+
+        endpoint::delete(&name).unwrap();
+    }
+
+    /// Output sink readable from the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn embedded_server_runs_commands_and_shuts_down() {
+        let name = format!("builder_test_{}", std::process::id());
+        let output = SharedBuffer::default();
+        let server = test_server(&name)
+            .env("PARTERM_TEST", "from env")
+            .env_remove("HOME")
+            .cwd("/")
+            .output(output.clone())
+            .run()
+            .unwrap();
+        assert_eq!(server.name(), name);
+
+        let client = Client::connect(&name).unwrap();
+        let script = RunCommand {
+            argv: vec!["echo $PARTERM_TEST ${HOME:-nohome}; pwd; sh -c 'exit 3'".to_string()],
+            raw: true,
+            ..Default::default()
+        };
+        let handle = client.run_command(script).unwrap();
+        assert_eq!(handle.wait().unwrap(), 3);
+        let output = String::from_utf8_lossy(&output.0.lock().unwrap()).to_string();
+        assert!(output.contains("from env"), "{}", output);
+        let screen = client.capture(0).unwrap().to_text();
+        assert!(screen.contains("from env nohome\n/\n"), "{}", screen);
+
+        // The shell exiting stops the server, pending commands fail
+        let handle = client.run(["exit"]).unwrap();
+        assert!(handle.wait().is_err());
+        assert_eq!(server.wait().unwrap(), 0);
+        assert!(Client::connect(&name).is_err());
+    }
+
+    #[test]
+    fn expected_output_is_matched_until_the_timeout() {
+        let name = format!("expect_test_{}", std::process::id());
+        let server = test_server(&name).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+
+        let expecting = {
+            let client = client.clone();
+            thread::spawn(move || client.expect(r"Listening on :(\d+)", None, 0))
+        };
+        thread::sleep(Duration::from_millis(300));
+        // The echo of the command line does not match
+        let ready = RunCommand {
+            argv: vec!["echo 'Listening' on :8080".to_string()],
+            raw: true,
+            ..Default::default()
+        };
+        client.execute(ready).unwrap();
+        let matched = expecting.join().unwrap().unwrap();
+        assert_eq!(matched.as_deref(), Some("Listening on :8080"));
+
+        let timeout = Some(Duration::from_millis(200));
+        assert_eq!(client.expect("Listening", timeout, 0).unwrap(), None);
+        let matched = client.expect(r":\d+$", timeout, 10).unwrap();
+        assert_eq!(matched.as_deref(), Some(":8080"));
+        assert!(client.expect("(", timeout, 0).is_err());
+
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn attached_clients_follow_the_terminal() {
+        let name = format!("attach_test_{}", std::process::id());
+        let server = test_server(&name).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        let mut writer = client.attach(false).unwrap();
+        let mut viewer = client.attach(true).unwrap();
+        let read = |attachment: &mut Attachment, text: &str| {
+            let mut output = String::new();
+            while !output.contains(text) {
+                let data = attachment.next().unwrap().unwrap();
+                output.push_str(&String::from_utf8_lossy(&data));
+            }
+            output
+        };
+
+        // The screen is repainted first
+        assert!(read(&mut viewer, "\x1b[2J").starts_with("\x1b[0m"));
+        writer.send_keys(b"echo typed $((6 * 7))\r").unwrap();
+        read(&mut writer, "typed 42");
+        read(&mut viewer, "typed 42");
+        assert!(viewer.send_keys(b"exit\r").is_err());
+
+        // Detaching does not stop the server
+        writer.detach().unwrap();
+        assert!(writer.next().is_none());
+        assert!(client.status().is_ok());
+
+        server.shutdown();
+        server.wait().unwrap();
+        // The viewer is left once the shell exited
+        for output in viewer {
+            output.unwrap();
+        }
+    }
+
+    #[test]
+    fn detached_servers_are_sized_by_the_clients() {
+        let name = format!("detached_test_{}", std::process::id());
+        let server = test_server(&name).detached(true).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        assert!(client.status().unwrap().detached);
+        let capture = client.capture(0).unwrap();
+        assert_eq!((capture.width, capture.height), (80, 24));
+
+        client.resize(100, 30).unwrap();
+        let capture = client.capture(0).unwrap();
+        assert_eq!((capture.width, capture.height), (100, 30));
+        assert_eq!(
+            client.run(["sh", "-c", "exit 4"]).unwrap().wait().unwrap(),
+            4
+        );
+
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn tasks_are_run_by_name() {
+        let name = format!("task_test_{}", std::process::id());
+        let test = RunCommand {
+            argv: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo $TASK in $PWD; exit 5".into(),
+            ],
+            cwd: Some("/tmp".into()),
+            env: vec![("TASK".into(), "test".into())],
+            ..Default::default()
+        };
+        let server = test_server(&name)
+            .detached(true)
+            .task("test", test)
+            .task("build", RunCommand::default())
+            .run()
+            .unwrap();
+        let client = Client::connect(&name).unwrap();
+        assert_eq!(client.status().unwrap().tasks, ["build", "test"]);
+
+        let mut handle = client.run_task("test", true).unwrap();
+        let output: Vec<u8> = handle
+            .output_stream()
+            .flat_map(|output| output.unwrap())
+            .collect();
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("test in /tmp"), "{}", output);
+        assert_eq!(handle.wait().unwrap(), 5);
+        // Without tee only the status comes back
+        let mut handle = client.run_task("test", false).unwrap();
+        assert_eq!(handle.output_stream().count(), 0);
+        assert_eq!(handle.wait().unwrap(), 5);
+        assert!(client.run_task("deploy", false).unwrap().wait().is_err());
+        assert!(client.execute_task("deploy").is_err());
+
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn clients_not_reading_their_output_do_not_block_the_terminal() {
+        let name = format!("stalled_test_{}", std::process::id());
+        let server = test_server(&name).detached(true).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        let flood = RunCommand {
+            argv: vec!["yes | head -n 300000; echo ALL''DONE".to_string()],
+            raw: true,
+            tee: true,
+            ..Default::default()
+        };
+        // The output of the command is never read
+        let handle = client.run_command(flood).unwrap();
+        let timeout = Some(Duration::from_secs(30));
+        let matched = client.expect("ALLDONE", timeout, 0).unwrap();
+        assert_eq!(matched.as_deref(), Some("ALLDONE"));
+        drop(handle);
+
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn other_directories_are_only_used_with_the_known_shells() {
+        let name = format!("dialect_test_{}", std::process::id());
+        // Like a REPL, nothing can be quoted for it
+        let server = test_server(&name)
+            .program("/bin/cat")
+            .cwd("/")
+            .detached(true)
+            .run()
+            .unwrap();
+        let client = Client::connect(&name).unwrap();
+        assert!(client.can_run_in(Path::new("/")).unwrap());
+        assert!(!client.can_run_in(Path::new("/tmp")).unwrap());
+        server.shutdown();
+        server.wait().unwrap();
+
+        let server = test_server(&name).detached(true).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        assert!(client.can_run_in(Path::new("/tmp")).unwrap());
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn servers_failing_to_start_leave_no_socket_behind() {
+        let name = format!("failed_start_test_{}", std::process::id());
+        let result = test_server(&name)
+            .record("/nonexistent/parterm/session.cast")
+            .run();
+        assert!(result.is_err());
+        assert!(!endpoint::endpoint_path(&name).unwrap().exists());
+        // The name can be used again
+        let server = test_server(&name).run().unwrap();
+        server.shutdown();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn servers_stop_when_the_shell_exits_leaving_background_jobs() {
+        let name = format!("background_test_{}", std::process::id());
+        let server = test_server(&name).detached(true).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        let exit = RunCommand {
+            argv: vec!["sleep 8 & exit 3".to_string()],
+            raw: true,
+            ..Default::default()
+        };
+        client.run_command(exit).unwrap();
+
+        let started = Instant::now();
+        assert_eq!(server.wait().unwrap(), 3);
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn shells_ignoring_the_hang_up_are_killed() {
+        let name = format!("nohup_test_{}", std::process::id());
+        let server = test_server(&name).detached(true).run().unwrap();
+        let client = Client::connect(&name).unwrap();
+        let status = client.run(["sh", "-c", "exit 0"]).unwrap().wait().unwrap();
+        assert_eq!(status, 0);
+        let ignore = RunCommand {
+            argv: vec!["trap '' HUP".to_string()],
+            raw: true,
+            wait: true,
+            ..Default::default()
+        };
+        client.run_command(ignore).unwrap().wait().unwrap();
+
+        server.shutdown();
+        assert_eq!(server.wait().unwrap(), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn queued_commands_run_when_a_prompt_hook_rewrites_ps1() {
+        let name = format!("ps1_test_{}", std::process::id());
+        let home = std::env::temp_dir().join(&name);
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(home.join(".bashrc"), "PROMPT_COMMAND='PS1=\"mine> \"'\n").unwrap();
+        let server = test_server(&name)
+            .program("/bin/bash")
+            .env("HOME", home.to_str().unwrap())
+            .detached(true)
+            .run()
+            .unwrap();
+        let client = Client::connect(&name).unwrap();
+        for status in [3, 4] {
+            let handle = client.run(["sh", "-c", &format!("exit {}", status)]);
+            assert_eq!(handle.unwrap().wait().unwrap(), status);
+        }
+        let timeout = Some(Duration::from_secs(5));
+        let matched = client.expect("mine> ", timeout, 24).unwrap();
+        assert_eq!(matched.as_deref(), Some("mine> "));
+        let info = client.status().unwrap();
+        assert_eq!((info.queued, info.waiting_ms), (0, None));
+
+        server.shutdown();
+        server.wait().unwrap();
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn prompt_hooks_ending_with_a_semicolon_keep_the_markers() {
+        let name = format!("hook_test_{}", std::process::id());
+        let home = std::env::temp_dir().join(&name);
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(home.join(".bashrc"), "PROMPT_COMMAND='true;'\n").unwrap();
+        let server = test_server(&name)
+            .program("/bin/bash")
+            .env("HOME", home.to_str().unwrap())
+            .detached(true)
+            .run()
+            .unwrap();
+        let client = Client::connect(&name).unwrap();
+        for status in [3, 4, 5] {
+            let handle = client.run(["sh", "-c", &format!("exit {}", status)]);
+            assert_eq!(handle.unwrap().wait().unwrap(), status);
+        }
+        let timeout = Some(Duration::from_millis(100));
+        let matched = client.expect("syntax error", timeout, 24).unwrap();
+        assert_eq!(matched, None);
+
+        server.shutdown();
+        server.wait().unwrap();
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn queued_commands_wait_for_the_running_program() {
+        let size = Size {
+            width: 80,
+            height: 24,
+        };
+        let mut pty = Pty::spawn("/bin/sh", &size).unwrap();
+        pty.write_all(b"sleep 10\n").unwrap();
+        let pty = Arc::new(pty);
+        assert!(wait_until(|| is_running_program(&pty)));
+
+        let (cmd_sender, cmd_receiver) = crossbeam_channel::unbounded();
+        let queue = CommandQueue::start(
+            pty.clone(),
+            cmd_sender,
+            SharedState::default(),
+            Waiters::default(),
+            "/bin/sh",
+        );
+        queue
+            .push(QueuedCommand {
+                cmd: b"ls\n".to_vec(),
+                waiter: None,
+                paste: false,
+            })
+            .unwrap();
+        assert!(queue.is_busy(&pty));
+        assert!(cmd_receiver.recv_timeout(COMMAND_SETTLE * 2).is_err());
+
+        pty.signal_foreground(libc::SIGINT).unwrap();
+        assert_eq!(
+            cmd_receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            b"ls\n"
+        );
+        thread::sleep(COMMAND_SETTLE * 2);
+        assert!(!queue.is_busy(&pty));
+        pty.signal(libc::SIGKILL).unwrap();
+        pty.wait().unwrap();
+    }
+
+    #[test]
+    fn pasted_commands_are_bracketed_once_the_shell_enabled_it() {
+        let size = Size {
+            width: 80,
+            height: 24,
+        };
+        let pty = Arc::new(Pty::spawn("/bin/sh", &size).unwrap());
+        let state = SharedState::default();
+        let (cmd_sender, cmd_receiver) = crossbeam_channel::unbounded();
+        let queue = CommandQueue::start(
+            pty.clone(),
+            cmd_sender,
+            state.clone(),
+            Waiters::default(),
+            "/bin/sh",
+        );
+        let script = || QueuedCommand {
+            cmd: b"cd /tmp\nls\n".to_vec(),
+            waiter: None,
+            paste: true,
+        };
+        let timeout = Duration::from_secs(5);
+
+        queue.push(script()).unwrap();
+        assert_eq!(
+            cmd_receiver.recv_timeout(timeout).unwrap(),
+            b"cd /tmp\nls\n"
+        );
+
+        state.lock().unwrap().modes.update(b"\x1b[?2004h$ ");
+        queue.push(script()).unwrap();
+        assert_eq!(
+            cmd_receiver.recv_timeout(timeout).unwrap(),
+            b"\x1b[200~cd /tmp\nls\x1b[201~\n"
+        );
+        pty.signal(libc::SIGKILL).unwrap();
+        pty.wait().unwrap();
+    }
+
+    #[test]
+    fn interrupted_programs_are_killed_when_they_ignore_the_signals() {
+        let size = Size {
+            width: 80,
+            height: 24,
+        };
+        let mut pty = Pty::spawn("/bin/sh", &size).unwrap();
+        pty.write_all(b"sh -c \"trap '' INT TERM; sleep 10\"\n")
+            .unwrap();
+        assert!(wait_until(|| is_running_program(&pty)));
+
+        let timeouts = InterruptTimeouts {
+            term_after_ms: 100,
+            kill_after_ms: 100,
+        };
+        let start = Instant::now();
+        interrupt_program(&pty, timeouts).unwrap();
+        assert!(wait_until(|| !is_running_program(&pty)));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        pty.signal(libc::SIGKILL).unwrap();
+        pty.wait().unwrap();
+    }
+
+    #[test]
+    fn only_the_server_user_and_the_allowed_users_are_authorized() {
+        let (stream, _) = UnixStream::pair().unwrap();
+        assert!(authorize(&stream, &[]).is_ok());
+
+        assert!(is_allowed(1000, 1000, &[]));
+        assert!(!is_allowed(1001, 1000, &[]));
+        assert!(is_allowed(1001, 1000, &[1002, 1001]));
+    }
+
+    #[test]
+    fn allowed_users_connect_through_a_shared_dir() {
+        // Connecting as another user needs root
+        if !nix::unistd::Uid::current().is_root() {
+            return;
+        }
+        let name = format!("shared_test_{}", std::process::id());
+        let dir = std::env::temp_dir().join(&name);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let server = test_server(&name)
+            .detached(true)
+            .shared_dir(&dir)
+            .allow_uid(65534)
+            .run()
+            .unwrap();
+        let connect_as = |uid: libc::uid_t| {
+            let (dir, name) = (dir.clone(), name.clone());
+            thread::spawn(move || {
+                // Unlike setuid, the system call only changes the user of this thread
+                let changed = unsafe {
+                    libc::syscall(libc::SYS_setresuid, libc::uid_t::MAX, uid, libc::uid_t::MAX)
+                };
+                assert_eq!(changed, 0);
+                Client::connect_shared(&dir, &name).and_then(|client| client.status())
+            })
+            .join()
+            .unwrap()
+        };
+        assert_eq!(connect_as(65534).unwrap().name, name);
+        let err = connect_as(65533).unwrap_err();
+        assert!(err.to_string().contains("PermissionDenied"), "{}", err);
+
+        server.shutdown();
+        server.wait().unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn commands_are_rendered_with_their_cwd_and_env() {
+        let render = |run: &RunCommand, shell| {
+            render_command(run, shell, Some(Path::new("/home")))
+                .map(|cmd| String::from_utf8(cmd).unwrap())
+        };
+        let mut run = RunCommand {
+            argv: vec!["grep".into(), "a b".into(), "file".into()],
+            ..Default::default()
+        };
+        assert_eq!(render(&run, "/bin/bash").unwrap(), "grep 'a b' file\n");
+        assert!(render(&run, "/usr/bin/nu").is_err());
+
+        run.env = vec![("LANG".into(), "C".into())];
+        assert_eq!(render(&run, "fish").unwrap(), "LANG=C grep 'a b' file\n");
+        run.cwd = Some("/my dir".into());
+        assert_eq!(
+            render(&run, "/bin/sh").unwrap(),
+            "(cd '/my dir' || exit; LANG=C grep 'a b' file)\n"
+        );
+        assert_eq!(
+            render(&run, "fish").unwrap(),
+            format!(
+                "{}; __parterm_cwd '/my dir' 'LANG=C grep \\'a b\\' file'\n",
+                FISH_CWD_FUNCTION
+            )
+        );
+        run.cd = true;
+        assert_eq!(
+            render(&run, "/bin/bash").unwrap(),
+            "cd '/my dir' && LANG=C grep 'a b' file\n"
+        );
+        run.cd = false;
+        run.env.clear();
+        // The shell is already there
+        run.cwd = Some("/home".into());
+        assert_eq!(render(&run, "/bin/sh").unwrap(), "grep 'a b' file\n");
+        run.env = vec![("LANG".into(), "C".into())];
+        run.cwd = Some("/my dir".into());
+
+        run.argv = vec!["make && ./run".into()];
+        run.raw = true;
+        assert_eq!(
+            render(&run, "/bin/zsh").unwrap(),
+            "(cd '/my dir' || exit; export LANG=C; make && ./run)\n"
+        );
+        run.cwd = None;
+        assert_eq!(
+            render(&run, "fish").unwrap(),
+            "begin; set -lx LANG C; make && ./run; end\n"
+        );
+
+        run.env = vec![("A B".into(), "C".into())];
+        assert!(render(&run, "/bin/sh").is_err());
+        assert!(render(&RunCommand::default(), "/bin/sh").is_err());
+    }
+
+    #[test]
+    fn status_scanner_finds_sequences_split_between_reads() {
+        use OutputEvent::*;
+        let mut scanner = SequenceScanner::default();
+        assert_eq!(
+            scanner.scan(b"output\x1b]69"),
+            vec![Data(b"output".to_vec())]
+        );
+        assert_eq!(
+            scanner.scan(b"73;1;2\x07more\x1b]6973;2;0\x07"),
+            vec![Status(1, 2), Data(b"more".to_vec()), Status(2, 0)]
+        );
+        assert_eq!(scanner.scan(b"\x1b]6973;3;1"), vec![]);
+        assert_eq!(
+            scanner.scan(b"27\x07$ "),
+            vec![Status(3, 127), Data(b"$ ".to_vec())]
+        );
+        assert!(scanner.pending.is_empty());
+    }
+
+    #[test]
+    fn scanner_finds_the_markers_and_leaves_other_sequences() {
+        use OutputEvent::*;
+        let mut scanner = SequenceScanner::default();
+        assert_eq!(
+            scanner.scan(b"\x1b]0;title\x07\x1b]133;D;1\x1b\\\x1b]133;A\x07$ \x1b]13"),
+            vec![
+                Data(b"\x1b]0;title\x07".to_vec()),
+                Marker(integration::Marker::CommandEnd(Some(1))),
+                Marker(integration::Marker::PromptStart),
+                Data(b"$ ".to_vec()),
+            ]
+        );
+        assert_eq!(
+            scanner.scan(b"3;C\x07out"),
+            vec![
+                Marker(integration::Marker::CommandStart),
+                Data(b"out".to_vec())
+            ]
+        );
+        assert!(scanner.pending.is_empty());
+    }
+
+    #[test]
+    fn integrated_commands_are_followed_with_the_markers() {
+        let (stream, mut client) = UnixStream::pair().unwrap();
+        let waiters = Waiters::default();
+        waiters
+            .lock()
+            .unwrap()
+            .insert(7, Waiter::new(7, stream, true));
+        let state = SharedState::default();
+        state.lock().unwrap().running = Some(7);
+
+        let mut scanner = SequenceScanner::default();
+        let output = b"make\r\n\x1b]133;C\x07Building\r\n\x1b]133;D;2\x07\x1b]133;A\x07$ ";
+        for event in scanner.scan(output) {
+            handle_event(event, &waiters, &state);
+        }
+        let reply: Option<Reply> = read_frame(&mut client).unwrap();
+        assert_eq!(
+            reply,
+            Some(Reply::Output {
+                data: b"Building\r\n".to_vec()
+            })
+        );
+        let reply: Option<Reply> = read_frame(&mut client).unwrap();
+        assert_eq!(reply, Some(Reply::Exited { status: 2 }));
+        assert!(waiters.lock().unwrap().is_empty());
+
+        let state = state.lock().unwrap();
+        assert!(state.integrated && state.at_prompt);
+        assert_eq!(state.running, None);
+    }
+
+    #[test]
+    fn prompts_without_their_end_marker_are_found_from_the_foreground() {
+        let size = Size {
+            width: 80,
+            height: 24,
+        };
+        let pty = Pty::spawn("/bin/sh", &size).unwrap();
+        let waiters = Waiters::default();
+        let state = SharedState::default();
+        // The shell marked the end of its first prompt, not of the next one
+        handle_marker(Marker::CommandInput, &waiters, &state);
+        handle_marker(Marker::CommandStart, &waiters, &state);
+        handle_marker(Marker::CommandEnd(Some(0)), &waiters, &state);
+        handle_marker(Marker::PromptStart, &waiters, &state);
+        assert!(!state.lock().unwrap().is_idle(&pty));
+
+        thread::sleep(MISSING_INPUT_MARK_DELAY * 2);
+        assert!(state.lock().unwrap().is_idle(&pty));
+        handle_marker(Marker::CommandStart, &waiters, &state);
+        assert!(!state.lock().unwrap().is_idle(&pty));
+    }
+
+    #[test]
+    fn shells_no_longer_printing_their_prompts_are_found_from_the_foreground() {
+        let size = Size {
+            width: 80,
+            height: 24,
+        };
+        let pty = Pty::spawn("/bin/sh", &size).unwrap();
+        let waiters = Waiters::default();
+        let state = SharedState::default();
+        // A status marker alone does not make the shell integrated
+        handle_marker(Marker::CommandStart, &waiters, &state);
+        assert!(!state.lock().unwrap().integrated);
+
+        handle_marker(Marker::CommandInput, &waiters, &state);
+        handle_marker(Marker::CommandStart, &waiters, &state);
+        state.lock().unwrap().running = Some(7);
+        assert!(!state.lock().unwrap().is_idle(&pty));
+
+        // No prompt followed the command
+        state.lock().unwrap().prompt_missing_since =
+            Some(Instant::now() - MISSING_PROMPT_TIMEOUT * 2);
+        let mut state = state.lock().unwrap();
+        assert!(state.is_idle(&pty));
+        assert!(!state.integrated);
+        assert_eq!(state.running, None);
+    }
+
+    #[test]
+    fn status_scanner_gives_up_on_unterminated_sequences() {
+        let mut scanner = SequenceScanner::default();
+        let mut data = b"\x1b]6973;".to_vec();
+        data.extend_from_slice(&[b'1'; MAX_SEQUENCE_LEN]);
+        let output: Vec<u8> = scanner
+            .scan(&data)
+            .into_iter()
+            .flat_map(|event| match event {
+                OutputEvent::Data(data) => data,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn wrapped_command_echoes_are_found_across_reads() {
+        let (stream, mut client) = UnixStream::pair().unwrap();
+        let waiters = Waiters::default();
+        let mut waiter = Waiter::new(7, stream, true);
+        waiter.wrapped = true;
+        waiters.lock().unwrap().insert(7, waiter);
+
+        let echo = b"make; printf '\\033]6973;%s;%s\\007' 7 $?\r\nBuilding\r\n";
+        for chunk in echo.chunks(3) {
+            tee_output(&waiters, chunk);
+        }
+        // Nothing comes when the echo is not found
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = Vec::new();
+        while output.len() < b"Building\r\n".len() {
+            match read_frame(&mut client).unwrap() {
+                Some(Reply::Output { data }) => output.extend(data),
+                reply => panic!("unexpected reply {:?}", reply),
+            }
+        }
+        assert_eq!(output, b"Building\r\n");
+    }
+
+    #[test]
+    fn wrap_with_status_uses_the_shell_status_variable() {
+        let wrapped = wrap_with_status(b"make\n", 4, "/usr/bin/fish");
+        assert_eq!(wrapped, b"make; printf '\\033]6973;%s;%s\\007' 4 $status\n");
+        let wrapped = wrap_with_status(b"make\n", 4, "/bin/bash");
+        assert_eq!(wrapped, b"make; printf '\\033]6973;%s;%s\\007' 4 $?\n");
     }
 }
@@ -0,0 +1,475 @@
+//! Wire protocol spoken between the parterm clients and server.
+//!
+//! A connection carries frames in both directions. A frame is the length of
+//! its payload as a 4 bytes big endian integer followed by the payload, a
+//! JSON encoded [`Request`] (client to server) or [`Reply`] (server to client).
+//!
+//! Every connection starts with a handshake: the client sends
+//! [`Request::Hello`] with its [`PROTOCOL_VERSION`], the server answers with
+//! [`Reply::Hello`] when it speaks the same version or with a
+//! [`ErrorKind::VersionMismatch`] error before closing the connection.
+//! The client then sends one request and reads the replies to it.
+
+use crate::screen::Capture;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+/// Version of the protocol, bumped on every incompatible change.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest payload accepted in a frame.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Messages sent by a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// First message of a connection
+    Hello {
+        version: u32,
+    },
+    /// Runs a command in the server shell
+    RunCommand(RunCommand),
+    /// Runs a task defined in the server, as its command with `wait` and
+    /// `tee` set as given
+    RunTask {
+        task: String,
+        #[serde(default)]
+        wait: bool,
+        #[serde(default)]
+        tee: bool,
+    },
+    /// Writes raw bytes to the server terminal as if they were typed
+    SendKeys {
+        data: Vec<u8>,
+    },
+    /// Types the named keys, like `C-c` or `Up`, in the server terminal, see
+    /// [`crate::keys`]
+    SendNamedKeys {
+        keys: Vec<String>,
+    },
+    /// Interrupts the program running in the server terminal
+    Interrupt,
+    /// Resizes the server terminal
+    Resize {
+        width: u16,
+        height: u16,
+    },
+    /// Asks for informations about the server
+    Query,
+    /// Waits for the server terminal to print text matching the regex
+    /// pattern, answered with [`Reply::Matched`] or a [`ErrorKind::Timeout`]
+    /// error once `timeout_ms` passed
+    Expect {
+        pattern: String,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Also match the last lines already printed
+        #[serde(default)]
+        recent: usize,
+    },
+    /// Follows the server terminal: its screen then its output are sent
+    /// with [`Reply::Output`] until the client closes the connection. Unless
+    /// read only, the client can send [`Request::SendKeys`] on the connection
+    Attach {
+        #[serde(default)]
+        read_only: bool,
+    },
+    /// Records the server terminal in the asciicast v2 format, in a file
+    /// written by the server
+    StartRecording {
+        path: PathBuf,
+    },
+    StopRecording,
+    /// Asks for what the server terminal shows and the last `history` lines
+    /// of its scrollback
+    Capture {
+        history: usize,
+    },
+}
+
+/// A command to run in the server shell.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunCommand {
+    /// The program and its arguments, quoted for the shell of the server
+    pub argv: Vec<String>,
+    /// The arguments are shell code typed as they are, joined with spaces
+    #[serde(default)]
+    pub raw: bool,
+    /// Directory to run the command in, defaults to the shell current directory
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// Change the directory of the shell itself to `cwd` instead of running
+    /// the command in a subshell
+    #[serde(default)]
+    pub cd: bool,
+    /// Environment variables set for the command
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// Report the command exit status with [`Reply::Exited`] instead of
+    /// replying [`Reply::Accepted`] right away
+    #[serde(default)]
+    pub wait: bool,
+    /// Stream the command output with [`Reply::Output`] until it exits,
+    /// implies `wait`
+    #[serde(default)]
+    pub tee: bool,
+    /// Type the command as a bracketed paste when the shell enabled them, so
+    /// that a multi-line command is read as a whole before it runs
+    #[serde(default)]
+    pub paste: bool,
+    /// What to do when a program is running in the shell
+    #[serde(default)]
+    pub policy: BusyPolicy,
+    /// How long an interrupted program has to exit, with [`BusyPolicy::Interrupt`]
+    #[serde(default)]
+    pub timeouts: InterruptTimeouts,
+}
+
+/// Delays before the interruption of a program escalates.
+///
+/// The program gets SIGINT, then SIGTERM once `term_after_ms` passed and
+/// SIGKILL once `kill_after_ms` more passed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptTimeouts {
+    pub term_after_ms: u64,
+    pub kill_after_ms: u64,
+}
+
+impl Default for InterruptTimeouts {
+    fn default() -> InterruptTimeouts {
+        InterruptTimeouts {
+            term_after_ms: 2000,
+            kill_after_ms: 2000,
+        }
+    }
+}
+
+/// What the server does with a command while the shell is busy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BusyPolicy {
+    /// Run the command once the shell is idle
+    #[default]
+    Queue,
+    /// Fail with [`ErrorKind::Busy`]
+    Reject,
+    /// Interrupt the running program, then run the command once the shell
+    /// is idle
+    Interrupt,
+}
+
+/// Messages sent by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    /// Answer to [`Request::Hello`]
+    Hello { version: u32 },
+    /// The request was handled
+    Accepted,
+    /// Part of the output of the command
+    Output { data: Vec<u8> },
+    /// The command finished with the given exit status
+    Exited { status: i32 },
+    /// Answer to [`Request::Query`]
+    Info(Box<ServerInfo>),
+    /// Answer to [`Request::Capture`]
+    Capture(Capture),
+    /// Answer to [`Request::Expect`], with the text matching the pattern
+    Matched { text: String },
+    /// The request failed
+    Error { kind: ErrorKind, message: String },
+}
+
+/// Informations about a running server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// Name of the connection
+    pub name: String,
+    /// Protocol version spoken by the server
+    pub version: u32,
+    /// Process id of the server
+    pub pid: u32,
+    /// Shell running in the server terminal
+    pub shell: String,
+    /// Process id of the shell
+    #[serde(default)]
+    pub shell_pid: u32,
+    /// Current directory of the foreground process of the server terminal
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// When the server started, in seconds since the Unix epoch
+    #[serde(default)]
+    pub started: u64,
+    /// A program started by the shell is running
+    #[serde(default)]
+    pub busy: bool,
+    /// The server runs without tty, see [`crate::parterm::ServerBuilder::detached`]
+    #[serde(default)]
+    pub detached: bool,
+    /// Commands queued and not typed yet
+    #[serde(default)]
+    pub queued: usize,
+    /// How long the next queued command has been waiting for the shell to
+    /// be idle, in milliseconds
+    #[serde(default)]
+    pub waiting_ms: Option<u64>,
+    /// Names of the tasks defined in the server
+    #[serde(default)]
+    pub tasks: Vec<String>,
+}
+
+/// Reasons for a request to fail.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The client and the server speak different protocol versions
+    VersionMismatch,
+    /// The client is not allowed to use the server
+    PermissionDenied,
+    /// The request could not be understood
+    InvalidRequest,
+    /// The server does not support the request
+    Unsupported,
+    /// The shell is running another program
+    Busy,
+    /// The request was not done in time
+    Timeout,
+    /// The server has no such task
+    NotFound,
+    /// The server failed to handle the request
+    Internal,
+}
+
+impl Reply {
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Reply {
+        Reply::Error {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+/// Writes a message as a single frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next frame and decodes its message.
+///
+/// Returns `None` if the connection was closed between two frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+/// Runs the client side of the handshake.
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> anyhow::Result<()> {
+    write_frame(
+        stream,
+        &Request::Hello {
+            version: PROTOCOL_VERSION,
+        },
+    )?;
+    match read_frame(stream)? {
+        Some(Reply::Hello { .. }) => Ok(()),
+        Some(Reply::Error { kind, message }) => anyhow::bail!("{:?}: {}", kind, message),
+        Some(reply) => anyhow::bail!("Unexpected reply to hello: {:?}", reply),
+        None => anyhow::bail!("The server closed the connection during the handshake"),
+    }
+}
+
+/// Runs the server side of the handshake.
+///
+/// On failure the client has been told why and the connection should be closed.
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> anyhow::Result<()> {
+    let reply = match read_frame(stream)? {
+        Some(Request::Hello { version }) if version == PROTOCOL_VERSION => {
+            write_frame(
+                stream,
+                &Reply::Hello {
+                    version: PROTOCOL_VERSION,
+                },
+            )?;
+            return Ok(());
+        }
+        Some(Request::Hello { version }) => Reply::error(
+            ErrorKind::VersionMismatch,
+            format!(
+                "client speaks version {}, server speaks version {}",
+                version, PROTOCOL_VERSION
+            ),
+        ),
+        Some(request) => Reply::error(
+            ErrorKind::InvalidRequest,
+            format!("expected hello, got {:?}", request),
+        ),
+        None => anyhow::bail!("The client closed the connection during the handshake"),
+    };
+    write_frame(stream, &reply)?;
+    anyhow::bail!("Handshake failed: {:?}", reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn round_trip<T: Serialize + DeserializeOwned>(message: &T) -> T {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, message).unwrap();
+        let mut reader = Cursor::new(buffer);
+        let decoded = read_frame(&mut reader).unwrap().unwrap();
+        assert!(read_frame::<_, T>(&mut reader).unwrap().is_none());
+        decoded
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = vec![
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+            },
+            Request::RunCommand(RunCommand {
+                argv: vec!["grep".into(), "a b".into(), "file".into()],
+                raw: false,
+                cwd: Some("/tmp".into()),
+                cd: true,
+                env: vec![("RUST_LOG".into(), "debug".into())],
+                wait: true,
+                tee: false,
+                paste: true,
+                policy: BusyPolicy::Reject,
+                timeouts: InterruptTimeouts::default(),
+            }),
+            Request::SendKeys {
+                data: vec![0x1b, b'[', b'A'],
+            },
+            Request::SendNamedKeys {
+                keys: vec!["C-c".into(), "Up".into(), "Enter".into()],
+            },
+            Request::Interrupt,
+            Request::Resize {
+                width: 80,
+                height: 24,
+            },
+            Request::Query,
+            Request::Capture { history: 100 },
+            Request::StartRecording {
+                path: "/tmp/build.cast".into(),
+            },
+            Request::StopRecording,
+            Request::Expect {
+                pattern: "Listening on :\\d+".into(),
+                timeout_ms: Some(30_000),
+                recent: 0,
+            },
+            Request::Attach { read_only: true },
+            Request::RunTask {
+                task: "test".into(),
+                wait: true,
+                tee: false,
+            },
+        ];
+        for request in requests {
+            assert_eq!(round_trip(&request), request);
+        }
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        let replies = vec![
+            Reply::Hello { version: 1 },
+            Reply::Accepted,
+            Reply::Output {
+                data: b"\x1b[1mbold\x1b[0m".to_vec(),
+            },
+            Reply::Exited { status: -1 },
+            Reply::Info(Box::new(ServerInfo {
+                name: "default".into(),
+                version: 1,
+                pid: 42,
+                shell: "/bin/bash".into(),
+                shell_pid: 43,
+                cwd: Some("/home".into()),
+                started: 1_600_000_000,
+                busy: true,
+                detached: false,
+                queued: 2,
+                waiting_ms: Some(1500),
+                tasks: vec!["build".into()],
+            })),
+            Reply::Capture(Capture {
+                width: 80,
+                height: 1,
+                cursor: (0, 2),
+                history: vec![vec![]],
+                screen: vec![vec![crate::screen::Span {
+                    text: "$ ".into(),
+                    attrs: Default::default(),
+                }]],
+            }),
+            Reply::Matched {
+                text: "Finished dev".into(),
+            },
+            Reply::error(ErrorKind::Unsupported, "nope"),
+        ];
+        for reply in replies {
+            assert_eq!(round_trip(&reply), reply);
+        }
+    }
+
+    #[test]
+    fn truncated_and_oversized_frames_are_errors() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Request::Query).unwrap();
+        buffer.pop();
+        assert!(read_frame::<_, Request>(&mut Cursor::new(buffer)).is_err());
+
+        let buffer = (MAX_FRAME_LEN + 1).to_be_bytes();
+        assert!(read_frame::<_, Request>(&mut Cursor::new(buffer)).is_err());
+    }
+
+    #[test]
+    fn handshake_rejects_other_versions() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Request::Hello { version: 0 }).unwrap();
+        let mut stream = Cursor::new(buffer);
+        assert!(server_handshake(&mut stream).is_err());
+
+        let written = stream.into_inner();
+        let mut reader = Cursor::new(written);
+        let _: Request = read_frame(&mut reader).unwrap().unwrap();
+        match read_frame(&mut reader).unwrap() {
+            Some(Reply::Error { kind, .. }) => assert_eq!(kind, ErrorKind::VersionMismatch),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+}
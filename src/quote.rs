@@ -0,0 +1,174 @@
+//! Quoting of the commands typed in the shell of the server.
+//!
+//! Arguments are single quoted, the quoting of each shell family is used for
+//! the quotes and the control characters in them. Control characters can not
+//! be typed as they are since the terminal would interpret them, the shells
+//! without escapes for them refuse them.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Quoting rules of a family of shells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// sh, dash, ksh and others following POSIX, no escapes for control characters
+    Posix,
+    /// bash and zsh, POSIX with the `$'...'` escapes
+    Bash,
+    /// fish, backslash escapes in single quotes
+    Fish,
+}
+
+impl Dialect {
+    /// Finds the quoting rules of a shell from the path of its program.
+    pub fn detect(shell: &str) -> Option<Dialect> {
+        match Path::new(shell).file_name()?.to_str()? {
+            "sh" | "dash" | "ash" | "ksh" | "mksh" | "busybox" => Some(Dialect::Posix),
+            "bash" | "zsh" => Some(Dialect::Bash),
+            "fish" => Some(Dialect::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// Characters never needing quotes.
+fn is_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_@%+:,./-".contains(c)
+}
+
+/// Quotes the argument so that the shell reads it back as it is.
+pub fn quote(arg: &str, dialect: Dialect) -> Result<String> {
+    if arg.contains('\0') {
+        bail!("{:?} contains a NUL byte", arg);
+    }
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return Ok(arg.to_string());
+    }
+    let has_control = arg.chars().any(|c| c.is_control());
+    match dialect {
+        Dialect::Posix if has_control => {
+            bail!(
+                "{:?} contains control characters the shell can not read",
+                arg
+            )
+        }
+        Dialect::Posix => Ok(format!("'{}'", arg.replace('\'', r"'\''"))),
+        Dialect::Bash if has_control => {
+            let mut quoted = String::from("$'");
+            for c in arg.chars() {
+                match c {
+                    '\\' | '\'' => {
+                        quoted.push('\\');
+                        quoted.push(c);
+                    }
+                    c if c.is_control() => escape_control(&mut quoted, c)?,
+                    c => quoted.push(c),
+                }
+            }
+            quoted.push('\'');
+            Ok(quoted)
+        }
+        Dialect::Bash => Ok(format!("'{}'", arg.replace('\'', r"'\''"))),
+        Dialect::Fish => {
+            // Control characters are escaped out of the quotes
+            let mut quoted = String::new();
+            let mut in_quotes = false;
+            for c in arg.chars() {
+                if c.is_control() {
+                    if in_quotes {
+                        quoted.push('\'');
+                        in_quotes = false;
+                    }
+                    escape_control(&mut quoted, c)?;
+                    continue;
+                }
+                if !in_quotes {
+                    quoted.push('\'');
+                    in_quotes = true;
+                }
+                if c == '\\' || c == '\'' {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            if in_quotes {
+                quoted.push('\'');
+            } else if quoted.is_empty() {
+                quoted.push_str("''");
+            }
+            Ok(quoted)
+        }
+    }
+}
+
+fn escape_control(quoted: &mut String, c: char) -> Result<()> {
+    if !c.is_ascii() {
+        bail!("{:?} can not be escaped", c);
+    }
+    quoted.push_str(&format!("\\x{:02x}", c as u32));
+    Ok(())
+}
+
+/// Quotes and joins the arguments into a command line.
+pub fn join<S: AsRef<str>>(argv: &[S], dialect: Dialect) -> Result<String> {
+    let quoted = argv
+        .iter()
+        .map(|arg| quote(arg.as_ref(), dialect))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(quoted.join(" "))
+}
+
+/// Checks the name can be used for an environment variable.
+pub fn check_variable_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("Invalid variable name {:?}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_are_quoted_for_each_shell() {
+        let argv = ["grep", "a b", "it's", "", "file"];
+        assert_eq!(
+            join(&argv, Dialect::Posix).unwrap(),
+            r"grep 'a b' 'it'\''s' '' file"
+        );
+        assert_eq!(
+            join(&argv, Dialect::Bash).unwrap(),
+            r"grep 'a b' 'it'\''s' '' file"
+        );
+        assert_eq!(
+            join(&argv, Dialect::Fish).unwrap(),
+            r"grep 'a b' 'it\'s' '' file"
+        );
+        assert_eq!(quote(r"a\b", Dialect::Fish).unwrap(), r"'a\\b'");
+        assert_eq!(quote("$HOME", Dialect::Posix).unwrap(), "'$HOME'");
+    }
+
+    #[test]
+    fn control_characters_are_escaped_or_refused() {
+        assert!(quote("a\nb", Dialect::Posix).is_err());
+        assert_eq!(quote("a\tb'", Dialect::Bash).unwrap(), r"$'a\x09b\''");
+        assert_eq!(quote("a\nb", Dialect::Fish).unwrap(), r"'a'\x0a'b'");
+        assert_eq!(quote("\n", Dialect::Fish).unwrap(), r"\x0a");
+        assert!(quote("a\0b", Dialect::Bash).is_err());
+    }
+
+    #[test]
+    fn variable_names_are_checked() {
+        assert!(check_variable_name("RUST_LOG").is_ok());
+        assert!(check_variable_name("_x1").is_ok());
+        assert!(check_variable_name("1x").is_err());
+        assert!(check_variable_name("A B").is_err());
+        assert!(check_variable_name("").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+//! Recording of the server terminal in the asciicast v2 format of asciinema.
+//!
+//! A recording is a JSON header line followed by a JSON line per event: the
+//! output of the terminal, the input written to it and its resizes, each
+//! with the seconds elapsed since the recording started.
+
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Writes the events of the terminal to a file.
+pub struct Recorder {
+    path: PathBuf,
+    file: LineWriter<File>,
+    start: Instant,
+    /// End of the output cut in the middle of a character
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl Recorder {
+    /// Starts a recording in the file, replacing it, for a terminal with the
+    /// given size running the shell.
+    pub fn create(path: &Path, width: u16, height: u16, shell: &str) -> io::Result<Recorder> {
+        let mut file = LineWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "env": {
+                "SHELL": shell,
+                "TERM": std::env::var("TERM").unwrap_or_default(),
+            },
+        });
+        writeln!(file, "{}", header)?;
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            file,
+            start: Instant::now(),
+            output: Vec::new(),
+            input: Vec::new(),
+        })
+    }
+
+    /// File the recording is written to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records the output of the terminal.
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let text = take_text(&mut self.output, data);
+        self.event("o", &text)
+    }
+
+    /// Records the input written to the terminal.
+    pub fn input(&mut self, data: &[u8]) -> io::Result<()> {
+        let text = take_text(&mut self.input, data);
+        self.event("i", &text)
+    }
+
+    /// Records the new size of the terminal.
+    pub fn resize(&mut self, width: u16, height: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", width, height))
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.file, "[{:.6}, {}, {}]", time, json!(kind), json!(data))
+    }
+}
+
+/// Returns the text of the data following the pending bytes, keeping in
+/// pending a character cut at the end.
+fn take_text(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let end = match std::str::from_utf8(pending) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..end]).into_owned();
+    pending.drain(..end);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn events_are_written_as_asciicast_lines() {
+        let path = std::env::temp_dir().join(format!("parterm-{}.cast", std::process::id()));
+        let mut recorder = Recorder::create(&path, 80, 24, "/bin/sh").unwrap();
+        recorder.input(b"ls\r").unwrap();
+        // The output is cut in the middle of a character
+        recorder.output(b"caf\xc3").unwrap();
+        recorder.output(b"\xa9\r\n").unwrap();
+        recorder.resize(100, 30).unwrap();
+        drop(recorder);
+
+        let recording = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["env"]["SHELL"], "/bin/sh");
+        let events: Vec<(&str, &str)> = lines[1..]
+            .iter()
+            .map(|event| (event[1].as_str().unwrap(), event[2].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("i", "ls\r"),
+                ("o", "caf"),
+                ("o", "\u{e9}\r\n"),
+                ("r", "100x30")
+            ]
+        );
+        assert!(lines[1..].iter().all(|event| event[0].is_f64()));
+    }
+}
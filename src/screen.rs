@@ -0,0 +1,957 @@
+//! Model of the server terminal, its screen and its scrollback.
+//!
+//! The output of the shell is parsed like a terminal does, to know what the
+//! screen shows at any time. Lines scrolled off the top of the screen are kept
+//! in the scrollback, except while a program uses the alternate screen.
+//!
+//! Zero width characters, like combining accents, are not kept.
+//!
+//! [`PlainText`] only strips the escape sequences from the output.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::mem;
+use unicode_width::UnicodeWidthChar;
+use vte::{Params, Parser, Perform};
+
+/// Default number of lines kept in the scrollback.
+pub const DEFAULT_SCROLLBACK: usize = 10_000;
+
+/// Size used when the terminal size is unknown, given as 0 like programs do.
+const DEFAULT_WIDTH: u16 = 80;
+const DEFAULT_HEIGHT: u16 = 24;
+
+/// Columns between two tab stops.
+const TAB_WIDTH: usize = 8;
+
+/// Color of the text or of its background.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    /// Color chosen by the terminal
+    #[default]
+    Default,
+    /// One of the 256 colors of the palette, the first 16 are the basic ones
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// How text is displayed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attrs {
+    #[serde(default, skip_serializing_if = "Color::is_default")]
+    pub fg: Color,
+    #[serde(default, skip_serializing_if = "Color::is_default")]
+    pub bg: Color,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dim: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub italic: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub underline: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub inverse: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub strikethrough: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Color {
+    fn is_default(&self) -> bool {
+        *self == Color::Default
+    }
+}
+
+/// Text displayed with the same attributes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    #[serde(flatten)]
+    pub attrs: Attrs,
+}
+
+/// A line of the screen or of the scrollback.
+pub type Line = Vec<Span>;
+
+/// What the terminal shows and the end of its scrollback.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// Size of the screen
+    pub width: u16,
+    pub height: u16,
+    /// Position of the cursor on the screen, row then column from 0
+    pub cursor: (u16, u16),
+    /// Lines of the scrollback, the oldest first
+    pub history: Vec<Line>,
+    /// Lines of the screen, from the top
+    pub screen: Vec<Line>,
+}
+
+/// A cell of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    /// The character shown, [`WIDE_SPACER`] for the cell after a wide character
+    c: char,
+    attrs: Attrs,
+}
+
+/// Content of the cell covered by the right half of a wide character.
+const WIDE_SPACER: char = '\0';
+
+type Row = Vec<Cell>;
+
+/// Position of the cursor.
+#[derive(Debug, Clone, Copy, Default)]
+struct Cursor {
+    row: usize,
+    col: usize,
+}
+
+/// What the terminal restores with `ESC 8`.
+#[derive(Debug, Clone, Copy, Default)]
+struct SavedCursor {
+    cursor: Cursor,
+    attrs: Attrs,
+}
+
+/// The primary screen, kept while the alternate one is shown.
+#[derive(Debug)]
+struct PrimaryScreen {
+    rows: Vec<Row>,
+    cursor: Cursor,
+}
+
+/// A terminal fed with the output of a program.
+pub struct Screen {
+    parser: Parser,
+    terminal: Terminal,
+}
+
+/// State of the terminal, changed by the parsed output.
+#[derive(Debug)]
+struct Terminal {
+    width: usize,
+    height: usize,
+    rows: Vec<Row>,
+    scrollback: VecDeque<Row>,
+    scrollback_limit: usize,
+    cursor: Cursor,
+    saved_cursor: SavedCursor,
+    attrs: Attrs,
+    /// The last column was written, the next character goes to the next line
+    wrap_pending: bool,
+    autowrap: bool,
+    /// First and last rows of the scrolling region
+    top: usize,
+    bottom: usize,
+    /// Set while the alternate screen is shown
+    primary: Option<PrimaryScreen>,
+}
+
+impl Screen {
+    /// Creates an empty screen with the given size, keeping at most
+    /// `scrollback` lines scrolled off the screen.
+    pub fn new(width: u16, height: u16, scrollback: usize) -> Screen {
+        Screen {
+            parser: Parser::new(),
+            terminal: Terminal::new(width, height, scrollback),
+        }
+    }
+
+    /// Updates the screen with the output of the program.
+    pub fn process(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.parser.advance(&mut self.terminal, byte);
+        }
+    }
+
+    /// Changes the size of the screen, the lines above the cursor going out
+    /// of the screen go to the scrollback.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let (width, height) = known_size(width, height);
+        self.terminal.resize(width, height);
+    }
+
+    /// Returns the width and the height of the screen.
+    pub fn size(&self) -> (u16, u16) {
+        (self.terminal.width as u16, self.terminal.height as u16)
+    }
+
+    /// Returns the screen and up to `history` lines of the scrollback.
+    pub fn capture(&self, history: usize) -> Capture {
+        let terminal = &self.terminal;
+        let skipped = terminal.scrollback.len().saturating_sub(history);
+        Capture {
+            width: terminal.width as u16,
+            height: terminal.height as u16,
+            cursor: (terminal.cursor.row as u16, terminal.cursor.col as u16),
+            history: terminal
+                .scrollback
+                .iter()
+                .skip(skipped)
+                .map(|row| spans(row))
+                .collect(),
+            screen: terminal.rows.iter().map(|row| spans(row)).collect(),
+        }
+    }
+}
+
+fn known_size(width: u16, height: u16) -> (usize, usize) {
+    let or_default = |value: u16, default: u16| if value == 0 { default } else { value };
+    (
+        or_default(width, DEFAULT_WIDTH) as usize,
+        or_default(height, DEFAULT_HEIGHT) as usize,
+    )
+}
+
+/// Groups the cells of the row by attributes, without the trailing blanks.
+fn spans(row: &[Cell]) -> Line {
+    let end = row
+        .iter()
+        .rposition(|cell| *cell != Cell::default())
+        .map_or(0, |last| last + 1);
+    let mut line: Line = Vec::new();
+    for cell in &row[..end] {
+        if cell.c == WIDE_SPACER {
+            continue;
+        }
+        match line.last_mut() {
+            Some(span) if span.attrs == cell.attrs => span.text.push(cell.c),
+            _ => line.push(Span {
+                text: cell.c.to_string(),
+                attrs: cell.attrs,
+            }),
+        }
+    }
+    line
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell {
+            c: ' ',
+            attrs: Attrs::default(),
+        }
+    }
+}
+
+impl Capture {
+    /// Returns the lines as plain text, without the blank lines at the end.
+    pub fn to_text(&self) -> String {
+        self.render(|line| line.iter().map(|span| span.text.as_str()).collect())
+    }
+
+    /// Returns the lines as text with the ANSI sequences of their attributes,
+    /// without the blank lines at the end.
+    pub fn to_ansi(&self) -> String {
+        self.render(ansi_line)
+    }
+
+    /// Returns the sequences drawing the screen on a terminal, with the
+    /// cursor at its place.
+    pub fn repaint(&self) -> String {
+        let mut repaint = String::from("\x1b[0m\x1b[H\x1b[2J");
+        for (row, line) in self.screen.iter().enumerate() {
+            let line = ansi_line(line);
+            if !line.is_empty() {
+                repaint.push_str(&format!("\x1b[{};1H{}", row + 1, line));
+            }
+        }
+        let (row, col) = self.cursor;
+        repaint.push_str(&format!("\x1b[{};{}H", row + 1, col + 1));
+        repaint
+    }
+
+    fn render(&self, render_line: impl Fn(&Line) -> String) -> String {
+        let mut lines: Vec<String> = self
+            .history
+            .iter()
+            .chain(&self.screen)
+            .map(render_line)
+            .collect();
+        while lines.last().is_some_and(|line| line.trim().is_empty()) {
+            lines.pop();
+        }
+        let mut text = lines.join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Returns the text of the line with the sequences of its attributes.
+fn ansi_line(line: &Line) -> String {
+    let mut text = String::new();
+    let mut attrs = Attrs::default();
+    for span in line {
+        if span.attrs != attrs {
+            text.push_str(&sgr(&span.attrs));
+            attrs = span.attrs;
+        }
+        text.push_str(&span.text);
+    }
+    if attrs != Attrs::default() {
+        text.push_str("\x1b[0m");
+    }
+    text
+}
+
+/// Returns the SGR sequence setting the attributes from the default ones.
+fn sgr(attrs: &Attrs) -> String {
+    let mut params = vec!["0".to_string()];
+    let flags = [
+        (attrs.bold, "1"),
+        (attrs.dim, "2"),
+        (attrs.italic, "3"),
+        (attrs.underline, "4"),
+        (attrs.inverse, "7"),
+        (attrs.strikethrough, "9"),
+    ];
+    params.extend(
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, param)| param.to_string()),
+    );
+    for (color, base) in [(attrs.fg, 30), (attrs.bg, 40)] {
+        match color {
+            Color::Default => {}
+            Color::Indexed(index @ 0..=7) => params.push((base + index as u16).to_string()),
+            Color::Indexed(index @ 8..=15) => {
+                params.push((base + 60 + index as u16 - 8).to_string())
+            }
+            Color::Indexed(index) => params.push(format!("{};5;{}", base + 8, index)),
+            Color::Rgb(r, g, b) => params.push(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        }
+    }
+    format!("\x1b[{}m", params.join(";"))
+}
+
+impl Terminal {
+    fn new(width: u16, height: u16, scrollback_limit: usize) -> Terminal {
+        let (width, height) = known_size(width, height);
+        Terminal {
+            width,
+            height,
+            rows: vec![vec![Cell::default(); width]; height],
+            scrollback: VecDeque::new(),
+            scrollback_limit,
+            cursor: Cursor::default(),
+            saved_cursor: SavedCursor::default(),
+            attrs: Attrs::default(),
+            wrap_pending: false,
+            autowrap: true,
+            top: 0,
+            bottom: height - 1,
+            primary: None,
+        }
+    }
+
+    /// A cell erased with the current background.
+    fn blank(&self) -> Cell {
+        Cell {
+            c: ' ',
+            attrs: Attrs {
+                bg: self.attrs.bg,
+                ..Attrs::default()
+            },
+        }
+    }
+
+    fn blank_row(&self) -> Row {
+        vec![self.blank(); self.width]
+    }
+
+    fn resize(&mut self, width: usize, height: usize) {
+        if self.cursor.row >= height {
+            // Keep the cursor line on the screen
+            let scrolled = self.cursor.row + 1 - height;
+            for row in self.rows.drain(..scrolled).collect::<Vec<_>>() {
+                self.push_scrollback(row);
+            }
+            self.cursor.row -= scrolled;
+        }
+        self.rows.resize(height, vec![Cell::default(); width]);
+        for row in &mut self.rows {
+            row.resize(width, Cell::default());
+        }
+        if let Some(primary) = &mut self.primary {
+            primary.rows.resize(height, vec![Cell::default(); width]);
+            for row in &mut primary.rows {
+                row.resize(width, Cell::default());
+            }
+            primary.cursor.row = primary.cursor.row.min(height - 1);
+            primary.cursor.col = primary.cursor.col.min(width - 1);
+        }
+        self.width = width;
+        self.height = height;
+        self.top = 0;
+        self.bottom = height - 1;
+        self.cursor.col = self.cursor.col.min(width - 1);
+        self.wrap_pending = false;
+    }
+
+    fn push_scrollback(&mut self, row: Row) {
+        if self.primary.is_some() || self.scrollback_limit == 0 {
+            return;
+        }
+        if self.scrollback.len() == self.scrollback_limit {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(row);
+    }
+
+    /// Moves the lines of the scrolling region up, the top ones going to the
+    /// scrollback when the region starts at the top of the screen.
+    fn scroll_up(&mut self, count: usize) {
+        let count = count.min(self.bottom + 1 - self.top);
+        for _ in 0..count {
+            let row = self.rows.remove(self.top);
+            if self.top == 0 {
+                self.push_scrollback(row);
+            }
+            let blank = self.blank_row();
+            self.rows.insert(self.bottom, blank);
+        }
+    }
+
+    fn scroll_down(&mut self, count: usize) {
+        let count = count.min(self.bottom + 1 - self.top);
+        for _ in 0..count {
+            self.rows.remove(self.bottom);
+            let blank = self.blank_row();
+            self.rows.insert(self.top, blank);
+        }
+    }
+
+    fn linefeed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.height {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.top {
+            self.scroll_down(1);
+        } else if self.cursor.row > 0 {
+            self.cursor.row -= 1;
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.wrap_pending = false;
+        self.cursor.row = row.min(self.height - 1);
+        self.cursor.col = col.min(self.width - 1);
+    }
+
+    /// Moves the cursor up or down, staying in the scrolling region when it
+    /// is in it.
+    fn move_rows(&mut self, rows: isize) {
+        let (min, max) = if (self.top..=self.bottom).contains(&self.cursor.row) {
+            (self.top, self.bottom)
+        } else {
+            (0, self.height - 1)
+        };
+        let row = (self.cursor.row as isize + rows).clamp(min as isize, max as isize);
+        self.move_to(row as usize, self.cursor.col);
+    }
+
+    fn erase(&mut self, row: usize, cols: std::ops::Range<usize>) {
+        let blank = self.blank();
+        let end = cols.end.min(self.width);
+        for cell in &mut self.rows[row][cols.start.min(end)..end] {
+            *cell = blank;
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            0 => {
+                self.erase(row, col..self.width);
+                for row in row + 1..self.height {
+                    self.erase(row, 0..self.width);
+                }
+            }
+            1 => {
+                for row in 0..row {
+                    self.erase(row, 0..self.width);
+                }
+                self.erase(row, 0..col + 1);
+            }
+            2 => {
+                for row in 0..self.height {
+                    self.erase(row, 0..self.width);
+                }
+            }
+            3 => self.scrollback.clear(),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let Cursor { row, col } = self.cursor;
+        match mode {
+            0 => self.erase(row, col..self.width),
+            1 => self.erase(row, 0..col + 1),
+            2 => self.erase(row, 0..self.width),
+            _ => {}
+        }
+    }
+
+    /// Inserts blank lines at the cursor, or deletes lines there when
+    /// `insert` is false, moving the lines below in the scrolling region.
+    fn insert_lines(&mut self, count: usize, insert: bool) {
+        if !(self.top..=self.bottom).contains(&self.cursor.row) {
+            return;
+        }
+        let count = count.min(self.bottom + 1 - self.cursor.row);
+        for _ in 0..count {
+            let blank = self.blank_row();
+            if insert {
+                self.rows.remove(self.bottom);
+                self.rows.insert(self.cursor.row, blank);
+            } else {
+                self.rows.remove(self.cursor.row);
+                self.rows.insert(self.bottom, blank);
+            }
+        }
+        self.cursor.col = 0;
+        self.wrap_pending = false;
+    }
+
+    /// Inserts blank cells at the cursor, or deletes cells there when
+    /// `insert` is false, moving the rest of the line.
+    fn insert_chars(&mut self, count: usize, insert: bool) {
+        let Cursor { row, col } = self.cursor;
+        let count = count.min(self.width - col);
+        let blank = self.blank();
+        let line = &mut self.rows[row];
+        for _ in 0..count {
+            if insert {
+                line.pop();
+                line.insert(col, blank);
+            } else {
+                line.remove(col);
+                line.push(blank);
+            }
+        }
+        self.wrap_pending = false;
+    }
+
+    fn set_private_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            7 => self.autowrap = enabled,
+            47 | 1047 => self.switch_screen(enabled),
+            1049 => {
+                if enabled {
+                    self.save_cursor();
+                    self.switch_screen(true);
+                } else {
+                    self.switch_screen(false);
+                    self.restore_cursor();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Shows the alternate screen, cleared, or goes back to the primary one.
+    fn switch_screen(&mut self, alternate: bool) {
+        match (alternate, self.primary.take()) {
+            (true, None) => {
+                let rows = vec![vec![Cell::default(); self.width]; self.height];
+                self.primary = Some(PrimaryScreen {
+                    rows: mem::replace(&mut self.rows, rows),
+                    cursor: self.cursor,
+                });
+            }
+            (false, Some(primary)) => {
+                self.rows = primary.rows;
+                self.cursor = primary.cursor;
+            }
+            (_, primary) => self.primary = primary,
+        }
+        self.wrap_pending = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = SavedCursor {
+            cursor: self.cursor,
+            attrs: self.attrs,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor;
+        self.attrs = saved.attrs;
+        self.move_to(saved.cursor.row, saved.cursor.col);
+    }
+
+    fn reset(&mut self) {
+        let scrollback = mem::take(&mut self.scrollback);
+        *self = Terminal {
+            scrollback,
+            ..Terminal::new(self.width as u16, self.height as u16, self.scrollback_limit)
+        };
+    }
+
+    fn set_graphic_rendition(&mut self, params: &Params) {
+        let params: Vec<&[u16]> = params.iter().collect();
+        if params.is_empty() {
+            self.attrs = Attrs::default();
+        }
+        let mut index = 0;
+        while index < params.len() {
+            let param = params[index];
+            index += 1;
+            match param[0] {
+                0 => self.attrs = Attrs::default(),
+                1 => self.attrs.bold = true,
+                2 => self.attrs.dim = true,
+                3 => self.attrs.italic = true,
+                4 => self.attrs.underline = param.get(1) != Some(&0),
+                7 => self.attrs.inverse = true,
+                9 => self.attrs.strikethrough = true,
+                21 => self.attrs.underline = true,
+                22 => {
+                    self.attrs.bold = false;
+                    self.attrs.dim = false;
+                }
+                23 => self.attrs.italic = false,
+                24 => self.attrs.underline = false,
+                27 => self.attrs.inverse = false,
+                29 => self.attrs.strikethrough = false,
+                code @ 30..=37 => self.attrs.fg = Color::Indexed((code - 30) as u8),
+                39 => self.attrs.fg = Color::Default,
+                code @ 40..=47 => self.attrs.bg = Color::Indexed((code - 40) as u8),
+                49 => self.attrs.bg = Color::Default,
+                code @ 90..=97 => self.attrs.fg = Color::Indexed((code - 90 + 8) as u8),
+                code @ 100..=107 => self.attrs.bg = Color::Indexed((code - 100 + 8) as u8),
+                code @ (38 | 48) => {
+                    let color = if param.len() > 1 {
+                        // Colon separated, 38:5:n or 38:2:[colorspace:]r:g:b
+                        extended_color(&param[1..])
+                    } else {
+                        let rest: Vec<u16> = params[index..].iter().map(|param| param[0]).collect();
+                        let (color, used) = match rest.first() {
+                            Some(5) => (extended_color(&rest[..rest.len().min(2)]), 2),
+                            Some(2) => (extended_color(&rest[..rest.len().min(4)]), 4),
+                            _ => (None, 0),
+                        };
+                        index += used;
+                        color
+                    };
+                    if let Some(color) = color {
+                        if code == 38 {
+                            self.attrs.fg = color;
+                        } else {
+                            self.attrs.bg = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses the color of a 38 or 48 SGR parameter, after its code.
+fn extended_color(params: &[u16]) -> Option<Color> {
+    match params {
+        [5, index] => Some(Color::Indexed(*index as u8)),
+        [2, r, g, b] | [2, _, r, g, b] => Some(Color::Rgb(*r as u8, *g as u8, *b as u8)),
+        _ => None,
+    }
+}
+
+impl Perform for Terminal {
+    fn print(&mut self, c: char) {
+        let width = match c.width() {
+            Some(width) if width > 0 => width.min(self.width),
+            _ => return,
+        };
+        if self.wrap_pending {
+            self.cursor.col = 0;
+            self.linefeed();
+        }
+        if self.cursor.col + width > self.width {
+            if !self.autowrap {
+                return;
+            }
+            self.cursor.col = 0;
+            self.linefeed();
+        }
+        let Cursor { row, col } = self.cursor;
+        let attrs = self.attrs;
+        self.rows[row][col] = Cell { c, attrs };
+        if width == 2 {
+            self.rows[row][col + 1] = Cell {
+                c: WIDE_SPACER,
+                attrs,
+            };
+        }
+        if col + width >= self.width {
+            self.cursor.col = self.width - 1;
+            self.wrap_pending = self.autowrap;
+        } else {
+            self.cursor.col = col + width;
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            // Backspace
+            0x08 => self.move_to(self.cursor.row, self.cursor.col.saturating_sub(1)),
+            // Tab
+            0x09 => {
+                let col = (self.cursor.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.move_to(self.cursor.row, col);
+            }
+            // Line feed, vertical tab and form feed
+            0x0a..=0x0c => self.linefeed(),
+            // Carriage return
+            0x0d => self.move_to(self.cursor.row, 0),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            return;
+        }
+        let values: Vec<u16> = params.iter().map(|param| param[0]).collect();
+        // Parameter at the index, 0 and missing ones being the default
+        let arg = |index: usize, default: u16| match values.get(index) {
+            Some(0) | None => default,
+            Some(&value) => value,
+        };
+        let count = arg(0, 1) as usize;
+        let Cursor { row, col } = self.cursor;
+        match (intermediates, action) {
+            ([], 'A') => self.move_rows(-(count as isize)),
+            ([], 'B' | 'e') => self.move_rows(count as isize),
+            ([], 'C' | 'a') => self.move_to(row, col + count),
+            ([], 'D') => self.move_to(row, col.saturating_sub(count)),
+            ([], 'E') => {
+                self.move_rows(count as isize);
+                self.cursor.col = 0;
+            }
+            ([], 'F') => {
+                self.move_rows(-(count as isize));
+                self.cursor.col = 0;
+            }
+            ([], 'G' | '`') => self.move_to(row, count - 1),
+            ([], 'H' | 'f') => self.move_to(count - 1, arg(1, 1) as usize - 1),
+            ([], 'd') => self.move_to(count - 1, col),
+            ([], 'J') => self.erase_display(arg(0, 0)),
+            ([], 'K') => self.erase_line(arg(0, 0)),
+            ([], 'L') => self.insert_lines(count, true),
+            ([], 'M') => self.insert_lines(count, false),
+            ([], '@') => self.insert_chars(count, true),
+            ([], 'P') => self.insert_chars(count, false),
+            ([], 'X') => self.erase(row, col..col + count),
+            ([], 'S') => self.scroll_up(count),
+            ([], 'T') => self.scroll_down(count),
+            ([], 'm') => self.set_graphic_rendition(params),
+            ([], 'r') => {
+                let top = arg(0, 1) as usize - 1;
+                let bottom = (arg(1, self.height as u16) as usize).min(self.height) - 1;
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.move_to(0, 0);
+                }
+            }
+            ([], 's') => self.save_cursor(),
+            ([], 'u') => self.restore_cursor(),
+            ([b'?'], 'h' | 'l') => {
+                for &mode in &values {
+                    self.set_private_mode(mode, action == 'h');
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+        match byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.linefeed();
+                self.cursor.col = 0;
+            }
+            b'M' => self.reverse_index(),
+            b'c' => self.reset(),
+            _ => {}
+        }
+    }
+}
+
+/// Removes the escape sequences from the output, keeping the text and its
+/// line breaks.
+#[derive(Default)]
+pub struct PlainText {
+    parser: Parser,
+    text: TextCollector,
+}
+
+#[derive(Default)]
+struct TextCollector(String);
+
+impl PlainText {
+    /// Returns the text of the output, the sequences can be split between calls.
+    pub fn strip(&mut self, data: &[u8]) -> String {
+        for &byte in data {
+            self.parser.advance(&mut self.text, byte);
+        }
+        mem::take(&mut self.text.0)
+    }
+}
+
+impl Perform for TextCollector {
+    fn print(&mut self, c: char) {
+        self.0.push(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        if byte == b'\n' || byte == b'\t' {
+            self.0.push(byte as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(screen: &Screen, history: usize) -> String {
+        screen.capture(history).to_text()
+    }
+
+    #[test]
+    fn lines_scrolled_off_the_screen_go_to_the_scrollback() {
+        let mut screen = Screen::new(10, 3, 2);
+        screen.process(b"one\r\ntwo\r\nthree\r\nfour\r\nfive\r\nsix");
+        assert_eq!(text(&screen, 0), "four\nfive\nsix\n");
+        assert_eq!(text(&screen, 1), "three\nfour\nfive\nsix\n");
+        // Only 2 lines are kept
+        assert_eq!(text(&screen, 10), "two\nthree\nfour\nfive\nsix\n");
+        assert_eq!(screen.capture(0).cursor, (2, 3));
+    }
+
+    #[test]
+    fn output_is_drawn_like_in_a_terminal() {
+        let mut screen = Screen::new(10, 5, 100);
+        // Long lines wrap, carriage returns and erases overwrite
+        screen.process(b"0123456789ab\r\n");
+        screen.process(b"50%\r100%\r\n");
+        screen.process(b"abcdef\x1b[3D\x1b[K\r\n");
+        screen.process(b"\x1b[1;8Hx\x1b[4;4Hend");
+        assert_eq!(text(&screen, 0), "0123456x89\nab\n100%\nabcend\n");
+
+        // Wide characters take two cells
+        screen.process(b"\x1b[2J\x1b[H\xe4\xbd\xa0\xe5\xa5\xbd!");
+        assert_eq!(text(&screen, 0), "\u{4f60}\u{597d}!\n");
+        assert_eq!(screen.capture(0).cursor, (0, 5));
+    }
+
+    #[test]
+    fn the_alternate_screen_leaves_the_primary_one_and_its_scrollback_alone() {
+        let mut screen = Screen::new(10, 2, 100);
+        screen.process(b"$ vim\r\n");
+        screen.process(b"\x1b[?1049h\x1b[Hfile\r\n\r\n\r\n~");
+        assert_eq!(text(&screen, 10), "\n~\n");
+        screen.process(b"\x1b[?1049l");
+        assert_eq!(text(&screen, 10), "$ vim\n");
+        assert_eq!(screen.capture(0).cursor, (1, 0));
+    }
+
+    #[test]
+    fn attributes_are_kept_and_rendered_with_ansi_sequences() {
+        let mut screen = Screen::new(20, 2, 100);
+        screen.process(b"\x1b[1;31merror\x1b[0m: \x1b[38;5;208mx\x1b[38:2::1:2:3my");
+        let capture = screen.capture(0);
+        let red = Attrs {
+            fg: Color::Indexed(1),
+            bold: true,
+            ..Attrs::default()
+        };
+        assert_eq!(
+            capture.screen[0],
+            vec![
+                Span {
+                    text: "error".into(),
+                    attrs: red
+                },
+                Span {
+                    text: ": ".into(),
+                    attrs: Attrs::default()
+                },
+                Span {
+                    text: "x".into(),
+                    attrs: Attrs {
+                        fg: Color::Indexed(208),
+                        ..Attrs::default()
+                    }
+                },
+                Span {
+                    text: "y".into(),
+                    attrs: Attrs {
+                        fg: Color::Rgb(1, 2, 3),
+                        ..Attrs::default()
+                    }
+                },
+            ]
+        );
+        assert_eq!(
+            capture.to_ansi(),
+            "\x1b[0;1;31merror\x1b[0m: \x1b[0;38;5;208mx\x1b[0;38;2;1;2;3my\x1b[0m\n"
+        );
+        let json = serde_json::to_string(&capture.screen[0][1]).unwrap();
+        assert_eq!(json, r#"{"text":": "}"#);
+
+        // Drawing the repaint gives the same screen
+        let mut copy = Screen::new(20, 2, 100);
+        copy.process(b"some\r\nthing");
+        copy.process(capture.repaint().as_bytes());
+        assert_eq!(copy.capture(0), capture);
+    }
+
+    #[test]
+    fn plain_text_has_no_sequences() {
+        let mut plain = PlainText::default();
+        assert_eq!(
+            plain.strip(b"\x1b[32m   Compiling\x1b[0m x\r\n\x1b]0;ti"),
+            "   Compiling x\n"
+        );
+        assert_eq!(plain.strip(b"tle\x07\x1b["), "");
+        assert_eq!(plain.strip(b"1mFinished\x1b[0m dev"), "Finished dev");
+    }
+
+    #[test]
+    fn resizing_keeps_the_cursor_line_on_the_screen() {
+        let mut screen = Screen::new(10, 4, 100);
+        screen.process(b"a\r\nb\r\nc\r\nd");
+        screen.resize(5, 2);
+        assert_eq!(text(&screen, 0), "c\nd\n");
+        assert_eq!(text(&screen, 10), "a\nb\nc\nd\n");
+        screen.resize(5, 3);
+        assert_eq!(text(&screen, 0), "c\nd\n");
+    }
+}
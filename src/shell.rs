@@ -8,9 +8,10 @@ pub mod pty {
     use std::io::{self, Read, Write};
     use std::ops;
     use std::os::unix::io::{FromRawFd, RawFd};
-    use std::os::unix::process::CommandExt;
-    use std::process::{Command, Stdio};
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{Child, Command, Stdio};
     use std::ptr;
+    use std::sync::Mutex;
 
     /// Master side of a pty master / slave pair.
     ///
@@ -20,6 +21,10 @@ pub mod pty {
         fd: RawFd,
         /// File built from fd to access Read and Write traits
         file: File,
+        /// Process id of the child running in the pty
+        pid: u32,
+        /// The child running in the pty, kept to reap it
+        child: Mutex<Child>,
     }
 
     /// Errors that might happen durring operations on pty.
@@ -31,6 +36,8 @@ pub mod pty {
         SpawnShell,
         /// Failed to resize the pty
         Resize,
+        /// Failed to get the foreground process group
+        ForegroundProcess,
     }
 
     impl Pty {
@@ -38,25 +45,46 @@ pub mod pty {
         /// given size in a newly created pty.
         /// Returns a Pty representing the master side controlling the pty.
         pub fn spawn(shell: &str, size: &Size) -> Result<Pty, PtyError> {
+            Pty::spawn_command(Command::new(shell), size)
+        }
+
+        /// Spawns the command, with its arguments, environment and current
+        /// directory, in a newly created pty with the given size.
+        /// Returns a Pty representing the master side controlling the pty.
+        pub fn spawn_command(mut cmd: Command, size: &Size) -> Result<Pty, PtyError> {
             let (master, slave) = openpty(size)?;
 
-            let mut cmd = Command::new(&shell);
+            // Each Stdio owns and closes its descriptor, so give each one a copy
+            let (stdout, stderr) = unsafe {
+                (
+                    libc::dup(slave)
+                        .to_result()
+                        .map_err(|_| PtyError::OpenPty)?,
+                    libc::dup(slave)
+                        .to_result()
+                        .map_err(|_| PtyError::OpenPty)?,
+                )
+            };
             cmd.stdin(unsafe { Stdio::from_raw_fd(slave) })
-                .stdout(unsafe { Stdio::from_raw_fd(slave) })
-                .stderr(unsafe { Stdio::from_raw_fd(slave) });
+                .stdout(unsafe { Stdio::from_raw_fd(stdout) })
+                .stderr(unsafe { Stdio::from_raw_fd(stderr) });
             unsafe {
                 cmd.pre_exec(before_exec);
             }
-            cmd.spawn().map_err(|_| PtyError::SpawnShell).and_then(|_| {
-                let pty = Pty {
-                    fd: master,
-                    file: unsafe { File::from_raw_fd(master) },
-                };
-
-                pty.resize(size)?;
-
-                Ok(pty)
-            })
+            cmd.spawn()
+                .map_err(|_| PtyError::SpawnShell)
+                .and_then(|child| {
+                    let pty = Pty {
+                        fd: master,
+                        file: unsafe { File::from_raw_fd(master) },
+                        pid: child.id(),
+                        child: Mutex::new(child),
+                    };
+
+                    pty.resize(size)?;
+
+                    Ok(pty)
+                })
         }
 
         /// Resizes the child pty.
@@ -68,6 +96,67 @@ pub mod pty {
                     .map_err(|_| PtyError::Resize)
             }
         }
+
+        /// Process id of the child running in the pty.
+        pub fn pid(&self) -> u32 {
+            self.pid
+        }
+
+        /// Sends the signal to the child.
+        pub fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+            unsafe {
+                if libc::kill(self.pid as libc::pid_t, signal) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+
+        /// Sends the signal to the process group of the child, which it leads.
+        pub fn signal_group(&self, signal: libc::c_int) -> io::Result<()> {
+            unsafe {
+                if libc::killpg(self.pid as libc::pid_t, signal) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+
+        /// Sends the signal to the process group in the foreground of the pty.
+        pub fn signal_foreground(&self, signal: libc::c_int) -> io::Result<()> {
+            let pgrp = self
+                .foreground_process_group()
+                .map_err(|_| io::Error::last_os_error())?;
+            unsafe {
+                if libc::killpg(pgrp as libc::pid_t, signal) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+
+        /// Waits for the child to exit and returns its exit code.
+        ///
+        /// A child killed by a signal exits with 128 plus the signal number,
+        /// like in shells.
+        pub fn wait(&self) -> io::Result<i32> {
+            let status = self.child.lock().unwrap().wait()?;
+            Ok(status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()))
+        }
+
+        /// Returns the id of the process group in the foreground of the pty.
+        ///
+        /// It is the child pid when no program started by the child is running.
+        pub fn foreground_process_group(&self) -> Result<u32, PtyError> {
+            unsafe {
+                libc::tcgetpgrp(self.fd)
+                    .to_result()
+                    .map(|pgrp| pgrp as u32)
+                    .map_err(|_| PtyError::ForegroundProcess)
+            }
+        }
     }
 
     /// Creates a pty with the given size and returns the (master, slave)
@@ -108,12 +197,12 @@ pub mod pty {
             // Create a new process group, this process being the master
             libc::setsid()
                 .to_result()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
+                .map_err(|_| io::Error::other(""))?;
 
             // Set this process as the controling terminal
             libc::ioctl(0, libc::TIOCSCTTY, 1)
                 .to_result()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
+                .map_err(|_| io::Error::other(""))?;
         }
 
         Ok(())
@@ -200,6 +289,17 @@ pub mod pty {
             assert!(output.starts_with("exit"));
         }
 
+        #[test]
+        fn wait_reports_the_signal_killing_the_child_like_shells() {
+            let size = Size {
+                width: 80,
+                height: 24,
+            };
+            let pty = Pty::spawn("/bin/sh", &size).unwrap();
+            pty.signal(libc::SIGKILL).unwrap();
+            assert_eq!(pty.wait().unwrap(), 128 + libc::SIGKILL);
+        }
+
         #[test]
         fn to_c_winsize_maps_width_to_col_height_to_row_and_sets_the_rest_to_0() {
             let expected = libc::winsize {
@@ -229,6 +329,7 @@ pub mod tui {
     use termion;
 
     /// A rectangular size in number of columns and rows
+    #[derive(Debug, Clone, PartialEq, Eq)]
     pub struct Size {
         /// Number of columns
         pub width: u16,